@@ -1,7 +1,11 @@
-use crate::fs::BLOCK_SIZE;
+use crate::fs::{SFSError, BLOCK_SIZE};
+use std::ops::Range;
 use zerocopy::{AsBytes, FromBytes};
 
-#[derive(Debug, PartialEq)]
+/// The number of blocks a single bitmap block is able to track.
+pub const BITMAP_CAPACITY: usize = BLOCK_SIZE * 8;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum State {
     Free,
     Used,
@@ -10,6 +14,8 @@ pub enum State {
 #[repr(C)]
 #[derive(AsBytes, FromBytes, Clone, Copy)]
 pub struct Bitmap {
+    /// Stored as little-endian words on disk, so bit `n` always lives in byte `n / 8`.
+    ///
     /// Stores 4096 bits mapping each bit to a logical block on disk. A 4K bitmap
     /// supports tracking up to 4096 * 8 logical blocks for a total of 32,768 blocks
     /// per bitmap block.
@@ -23,17 +29,31 @@ impl Bitmap {
         }
     }
 
-    pub fn parse(buf: &[u8]) -> Self {
-        let map: *const Bitmap = buf.as_ptr() as *const Bitmap;
-        unsafe { *map }
+    /// Parses a bitmap from a disk block, returning an error if the buffer is smaller
+    /// than a block.
+    pub fn parse(buf: &[u8]) -> Result<Self, SFSError> {
+        if buf.len() < BLOCK_SIZE {
+            return Err(SFSError::Corrupted("bitmap block is truncated".to_string()));
+        }
+        let mut map = Bitmap::new();
+        map.as_bytes_mut().copy_from_slice(&buf[..BLOCK_SIZE]);
+        Ok(map.swap_le())
     }
 
-    pub fn serialize(&self) -> &[u8] {
-        self.as_bytes()
+    pub fn serialize(&self) -> Vec<u8> {
+        self.swap_le().as_bytes().to_vec()
+    }
+
+    /// Converts every word between host and little-endian byte order.
+    fn swap_le(mut self) -> Self {
+        for word in self.bitmap.iter_mut() {
+            *word = word.to_le();
+        }
+        self
     }
 
     pub fn get(&self, blocknr: usize) -> State {
-        assert!(blocknr < (4096 * 8 - 1));
+        assert!(blocknr < BITMAP_CAPACITY);
         // Grab of the u64 containing the significant bit.
         let outer_offset = self.bitmap[blocknr / 64];
 
@@ -48,7 +68,7 @@ impl Bitmap {
     }
 
     pub fn set_reserved(&mut self, blocknr: usize) {
-        assert!(blocknr < (4096 * 8 - 1));
+        assert!(blocknr < BITMAP_CAPACITY);
         // Grab of the u64 containing the significant bit.
         let outer_offset = self.bitmap[blocknr / 64];
 
@@ -57,15 +77,40 @@ impl Bitmap {
         self.bitmap[blocknr / 64] = outer_offset | mask;
     }
 
-    #[allow(dead_code)]
     pub fn set_free(&mut self, blocknr: usize) {
-        assert!(blocknr < (4096 * 8 - 1));
+        assert!(blocknr < BITMAP_CAPACITY);
         // Grab of the u64 containing the significant bit.
         let outer_offset = self.bitmap[blocknr / 64];
 
         let inner_offset = blocknr % 64;
-        let mask = 0b00_u64 << inner_offset;
-        self.bitmap[blocknr / 64] = outer_offset & mask;
+        let mask = 0b01_u64 << inner_offset;
+        self.bitmap[blocknr / 64] = outer_offset & !mask;
+    }
+
+    /// Counts the blocks in the first `cap` entries of the bitmap matching `state`.
+    pub fn count(&self, state: State, cap: usize) -> usize {
+        (0..cap).filter(|&i| self.get(i) == state).count()
+    }
+
+    /// Returns every run of consecutive free blocks in the first `cap` entries of
+    /// the bitmap, in ascending order.
+    pub fn free_extents(&self, cap: usize) -> Vec<Range<usize>> {
+        let mut extents = vec![];
+        let mut start = None;
+        for i in 0..cap {
+            match (self.get(i), start) {
+                (State::Free, None) => start = Some(i),
+                (State::Used, Some(s)) => {
+                    extents.push(s..i);
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(s) = start {
+            extents.push(s..cap);
+        }
+        extents
     }
 }
 
@@ -91,7 +136,7 @@ pub struct NextAvailableAllocation {
 
 impl NextAvailableAllocation {
     pub fn new(bitmap: Bitmap, cap: Option<usize>) -> Self {
-        let cap = cap.unwrap_or_else(|| BLOCK_SIZE / 8);
+        let cap = cap.unwrap_or(BLOCK_SIZE / 8);
         Self {
             marker: 0,
             bitmap,
@@ -139,6 +184,15 @@ mod tests {
         assert_eq!(bmp.get(4095), State::Used);
     }
 
+    #[test]
+    fn can_set_last_bit_of_bitmap() {
+        let mut bmp = Bitmap::new();
+
+        bmp.set_reserved(BITMAP_CAPACITY - 1);
+
+        assert_eq!(bmp.get(BITMAP_CAPACITY - 1), State::Used);
+    }
+
     #[test]
     fn can_toggle_block_between_free_and_used() {
         let mut bmp = Bitmap::new();
@@ -150,6 +204,20 @@ mod tests {
         assert_eq!(bmp.get(10), State::Free);
     }
 
+    #[test]
+    fn freeing_a_block_leaves_neighbors_reserved() {
+        let mut bmp = Bitmap::new();
+        bmp.set_reserved(9);
+        bmp.set_reserved(10);
+        bmp.set_reserved(11);
+
+        bmp.set_free(10);
+
+        assert_eq!(bmp.get(9), State::Used);
+        assert_eq!(bmp.get(10), State::Free);
+        assert_eq!(bmp.get(11), State::Used);
+    }
+
     #[test]
     fn can_serialize_and_deserialize_state() {
         let mut bmp = Bitmap::new();
@@ -157,7 +225,7 @@ mod tests {
         bmp.set_reserved(11);
         bmp.set_reserved(12);
 
-        let read_bmp = Bitmap::parse(bmp.serialize());
+        let read_bmp = Bitmap::parse(&bmp.serialize()).unwrap();
         // This is a dumb way of testing equality between two arrays of different
         // lengths. I can't derive debug for the arrays because they exceed the max
         // trait implementation limit, see: https://doc.rust-lang.org/std/primitive.array.html.
@@ -166,4 +234,42 @@ mod tests {
             true
         });
     }
+
+    #[test]
+    fn encodes_bits_in_ascending_byte_order() {
+        let mut bmp = Bitmap::new();
+        bmp.set_reserved(0);
+        bmp.set_reserved(9);
+        bmp.set_reserved(63);
+
+        let encoded = bmp.serialize();
+        assert_eq!(&encoded[0..8], &[0x01, 0x02, 0, 0, 0, 0, 0, 0x80]);
+        assert_eq!(Bitmap::parse(&encoded).unwrap().get(63), State::Used);
+    }
+
+    #[test]
+    fn parsing_truncated_bitmap_returns_error() {
+        assert!(Bitmap::parse(&[0xFF; 64]).is_err());
+    }
+
+    #[test]
+    fn can_count_blocks_by_state() {
+        let mut bmp = Bitmap::new();
+        bmp.set_reserved(0);
+        bmp.set_reserved(3);
+        bmp.set_reserved(20);
+
+        assert_eq!(bmp.count(State::Used, 10), 2);
+        assert_eq!(bmp.count(State::Free, 10), 8);
+    }
+
+    #[test]
+    fn free_extents_are_split_by_used_blocks() {
+        let mut bmp = Bitmap::new();
+        bmp.set_reserved(0);
+        bmp.set_reserved(3);
+        bmp.set_reserved(4);
+
+        assert_eq!(bmp.free_extents(8), vec![1..3, 5..8]);
+    }
 }
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+
+/// The most entries kept before the cache starts over, bounding the memory used by negative
+/// entries for names that were looked up but never existed.
+pub(crate) const DEFAULT_CAPACITY: usize = 1024;
+
+/// Lookup counters for the directory entry cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// The number of entries currently cached, negative entries included.
+    pub entries: usize,
+}
+
+/// Caches the result of looking up a name in a directory, keyed by the directory's inumber
+/// and the name exactly as it was looked up. Misses are cached as negative entries so
+/// repeated lookups of missing names don't re-read the directory either.
+pub struct DentryCache {
+    entries: HashMap<(u32, OsString), Option<u32>>,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl DentryCache {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the cached result of looking up `name` in `dir`: Some(None) for a cached miss
+    /// and None if nothing is cached.
+    pub fn get(&mut self, dir: u32, name: &OsStr) -> Option<Option<u32>> {
+        let entry = self.entries.get(&(dir, name.to_os_string())).copied();
+        match entry {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        entry
+    }
+
+    pub fn insert(&mut self, dir: u32, name: &OsStr, inum: Option<u32>) {
+        if self.entries.len() >= self.capacity {
+            self.entries.clear();
+        }
+        self.entries.insert((dir, name.to_os_string()), inum);
+    }
+
+    /// Drops every entry looked up in `dir`, called whenever its contents change.
+    pub fn invalidate_dir(&mut self, dir: u32) {
+        self.entries.retain(|(parent, _), _| *parent != dir);
+    }
+
+    /// Drops every entry, keeping the hit and miss counts.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_positive_and_negative_entries() {
+        let mut cache = DentryCache::new();
+        cache.insert(0, OsStr::new("foo"), Some(1));
+        cache.insert(0, OsStr::new("bar"), None);
+
+        assert_eq!(cache.get(0, OsStr::new("foo")), Some(Some(1)));
+        assert_eq!(cache.get(0, OsStr::new("bar")), Some(None));
+        assert_eq!(cache.get(1, OsStr::new("foo")), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                entries: 2
+            }
+        );
+    }
+
+    #[test]
+    fn invalidating_a_directory_keeps_other_directories() {
+        let mut cache = DentryCache::new();
+        cache.insert(0, OsStr::new("foo"), Some(1));
+        cache.insert(0, OsStr::new("missing"), None);
+        cache.insert(1, OsStr::new("bar"), Some(2));
+
+        cache.invalidate_dir(0);
+
+        assert_eq!(cache.get(0, OsStr::new("foo")), None);
+        assert_eq!(cache.get(0, OsStr::new("missing")), None);
+        assert_eq!(cache.get(1, OsStr::new("bar")), Some(Some(2)));
+    }
+
+    #[test]
+    fn starts_over_once_full() {
+        let mut cache = DentryCache::with_capacity(2);
+        cache.insert(0, OsStr::new("a"), None);
+        cache.insert(0, OsStr::new("b"), None);
+        cache.insert(0, OsStr::new("c"), None);
+
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.get(0, OsStr::new("c")), Some(None));
+    }
+}
@@ -1,24 +1,51 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
 
-use crate::alloc::{Bitmap, NextAvailableAllocation};
+use crate::alloc::{Bitmap, NextAvailableAllocation, State, BITMAP_CAPACITY};
+use crate::dcache::{CacheStats, DentryCache};
 use crate::io::BlockStorage;
-use crate::node::InodeGroup;
-use crate::sb::SuperBlock;
+use crate::node::{
+    Inode, InodeGroup, CASEFOLD_FL, CHR_MODE, DIRECT_BLOCKS, FILE_TYPE_MASK, FIRST_INODE,
+    LOST_FOUND_INODE, MKNOD_TYPES, ROOT_INODE, SUPPORTED_FLAGS,
+};
+use crate::options::{
+    AtimePolicy, ErrorPolicy, MountOptions, SfsOptions, SyncPolicy, DEFAULT_COMMIT_INTERVAL,
+};
+use crate::path::{SfsPath, MAX_NAME_LEN};
+use crate::sb::{
+    crc32, SuperBlock, CURRENT_VERSION, RESERVED_INODES_FEATURE, SEALED_FEATURE, SUPPORTED_FEATURES,
+};
+use crate::watch::{Event, EventKind, Watchers};
 
-use std::collections::HashMap;
-use std::ffi::OsString;
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 
-const SB_MAGIC: u32 = 0x5346_5342; // SFSB
+pub(crate) const SB_MAGIC: u32 = 0x5346_5342; // SFSB
 
 pub const BLOCK_SIZE: usize = 4096;
-const NODE_SIZE: usize = 256;
+pub(crate) const NODE_SIZE: usize = 256;
 
 /// Known locations.
 const SUPERBLOCK_INDEX: usize = 0;
 const DATA_REGION_BMP: usize = 1;
 const INODE_BMP: usize = 2;
 const INODE_START: usize = 3;
+const DATA_START: usize = 8;
+const NODES_PER_BLOCK: usize = BLOCK_SIZE / NODE_SIZE;
+
+/// The name of the directory in the root orphans are reconnected to.
+const LOST_FOUND_NAME: &str = "lost+found";
+
+/// The number of blocks on each line of `SFS::block_map`.
+const BLOCK_MAP_WIDTH: usize = 64;
+
+/// The shortest the flusher sleeps between checks, so a zero commit interval doesn't spin.
+const MIN_FLUSH_WAIT: Duration = Duration::from_millis(10);
 
 impl Default for SuperBlock {
     fn default() -> Self {
@@ -33,11 +60,15 @@ impl Default for SuperBlock {
         sb.free_blocks_count = 0;
         // All inodes are initially free.
         sb.free_inodes_count = sb.inodes_count;
+        sb.max_name_len = MAX_NAME_LEN;
+        sb.version = CURRENT_VERSION;
+        sb.features = RESERVED_INODES_FEATURE;
         sb
     }
 }
 
 // Encodes open filesystem call options http://man7.org/linux/man-pages/man2/open.2.html.
+#[allow(clippy::upper_case_acronyms)]
 pub enum OpenMode {
     RO,
     WO,
@@ -54,16 +85,197 @@ pub enum SFSError {
     DoesNotExist,
     #[error("invalid file system block layout")]
     InvalidBlock(#[from] std::io::Error),
+    #[error("file system is mounted read-only")]
+    ReadOnly,
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("is a directory")]
+    IsADirectory,
+    #[error("no space left on device")]
+    NoSpace,
+    #[error("file name too long")]
+    NameTooLong,
+    #[error("file already exists")]
+    Exists,
+    #[error("operation not permitted")]
+    NotPermitted,
+    #[error("directory not empty")]
+    NotEmpty,
+    #[error("file system structure is corrupted: {0}")]
+    Corrupted(String),
+    #[error("not a simplefs image")]
+    BadMagic,
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("superblock checksum mismatch")]
+    BadChecksum,
+    #[error("stale file handle")]
+    Stale,
+    #[error("file system is in use by another mount")]
+    Busy,
+}
+
+impl SFSError {
+    /// Maps the error to the `errno` value reported back to callers of the file system,
+    /// e.g. the reply to a FUSE request.
+    pub fn to_errno(&self) -> libc::c_int {
+        match self {
+            SFSError::InvalidArgument(_) => libc::EINVAL,
+            SFSError::DoesNotExist => libc::ENOENT,
+            SFSError::InvalidBlock(err) => err.raw_os_error().unwrap_or(libc::EIO),
+            SFSError::ReadOnly => libc::EROFS,
+            SFSError::NotADirectory => libc::ENOTDIR,
+            SFSError::IsADirectory => libc::EISDIR,
+            SFSError::NoSpace => libc::ENOSPC,
+            SFSError::NameTooLong => libc::ENAMETOOLONG,
+            SFSError::Exists => libc::EEXIST,
+            SFSError::NotPermitted => libc::EPERM,
+            SFSError::NotEmpty => libc::ENOTEMPTY,
+            #[cfg(target_os = "linux")]
+            SFSError::Corrupted(_) => libc::EUCLEAN,
+            #[cfg(not(target_os = "linux"))]
+            SFSError::Corrupted(_) => libc::EIO,
+            SFSError::BadMagic | SFSError::UnsupportedVersion(_) => libc::EINVAL,
+            SFSError::BadChecksum => libc::EBADMSG,
+            SFSError::Stale => libc::ESTALE,
+            SFSError::Busy => libc::EBUSY,
+        }
+    }
+}
+
+/// A point-in-time summary of space usage in the file system, similar to what
+/// `statfs(2)` reports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsStats {
+    /// The size in bytes of a single block.
+    pub block_size: usize,
+    /// The number of blocks in the data region.
+    pub total_blocks: usize,
+    pub used_blocks: usize,
+    pub free_blocks: usize,
+    /// The number of inodes the file system can hold.
+    pub total_inodes: usize,
+    pub used_inodes: usize,
+    pub free_inodes: usize,
+    /// The length in blocks of the longest run of free data blocks.
+    pub largest_free_extent: usize,
+    /// The percentage of free data blocks that fall outside the largest free
+    /// extent. Zero means all free space is contiguous.
+    pub fragmentation: f64,
+    /// Whether modifications are rejected, like `ST_RDONLY`.
+    pub read_only: bool,
+    /// When access times are updated, like `ST_NOATIME` and `ST_RELATIME`.
+    pub atime: AtimePolicy,
+}
+
+/// Space usage before and after a defragmentation pass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefragReport {
+    pub before: FsStats,
+    pub after: FsStats,
+    /// The number of data blocks relocated by the pass.
+    pub blocks_moved: usize,
+}
+
+/// Identifies a file independently of its path, stable across remounts. The generation
+/// keeps a handle from resolving to a different file that later reuses the inumber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileHandle {
+    pub ino: u32,
+    pub generation: u32,
+}
+
+impl FileHandle {
+    /// Encodes the handle for storing outside the file system, e.g. in a backup index.
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut buf = [0; 8];
+        buf[..4].copy_from_slice(&self.ino.to_le_bytes());
+        buf[4..].copy_from_slice(&self.generation.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: [u8; 8]) -> Self {
+        let mut ino = [0; 4];
+        let mut generation = [0; 4];
+        ino.copy_from_slice(&buf[..4]);
+        generation.copy_from_slice(&buf[4..]);
+        Self {
+            ino: u32::from_le_bytes(ino),
+            generation: u32::from_le_bytes(generation),
+        }
+    }
+}
+
+/// Counts of the block operations issued to the underlying device since mounting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoStats {
+    pub reads: u64,
+    pub writes: u64,
+    /// The number of times buffered metadata was written out and the device synced.
+    pub flushes: u64,
+}
+
+/// Metadata changed in memory that hasn't been written to disk yet.
+#[derive(Clone, Default)]
+struct DirtyMetadata {
+    data_map: bool,
+    inode_map: bool,
+    /// Indexes into the inode table of the blocks holding modified inodes.
+    inode_blocks: BTreeSet<usize>,
+    /// When the oldest unwritten change was made, None if nothing is dirty.
+    since: Option<Instant>,
+}
+
+/// The in-memory state saved when a transaction starts, restored if it fails.
+struct Transaction {
+    data_map: Bitmap,
+    inodes: InodeGroup,
+    dirty: DirtyMetadata,
+    /// Events held back until the transaction commits.
+    events: Vec<Event>,
+}
+
+/// Everything stored in an inode, as reported by `SFS::istat`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InodeStat {
+    pub ino: u32,
+    /// The file type and permission bits.
+    pub mode: u16,
+    /// The stored owner, without the `uid` and `gid` mount options applied.
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u32,
+    pub flags: u32,
+    pub rdev: u32,
+    pub generation: u32,
+    /// The data blocks holding the file's contents, in file order.
+    pub blocks: Vec<u32>,
+}
+
+/// The number of data blocks consumed by a directory and everything beneath it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirUsage {
+    pub path: PathBuf,
+    pub blocks: usize,
 }
 
-/// A fixed 64 4k block file system. Currently hard coded for simplicity with
-/// one super block, one inode bitmap, one data block bitmap, five inode blocks,
-/// and 56 blocks for data storage.
+/// A 4k block file system, 64 blocks in size by default. Currently hard coded for
+/// simplicity with one super block, one inode bitmap, one data block bitmap, five
+/// inode blocks, and 56 blocks for data storage unless the data region is resized.
 pub struct SFS<T: BlockStorage> {
     dev: T,
     super_block: SuperBlock,
     data_map: Bitmap,
     inodes: InodeGroup,
+    options: MountOptions,
+    /// Set once corruption is found under `ErrorPolicy::RemountReadOnly`, rejecting every
+    /// modification until the file system is mounted again.
+    errored: Cell<bool>,
+    dcache: DentryCache,
+    dirty: DirtyMetadata,
+    io: IoStats,
+    watchers: Watchers,
+    txn: Option<Transaction>,
 }
 
 impl<T: BlockStorage> SFS<T> {
@@ -73,336 +285,3357 @@ impl<T: BlockStorage> SFS<T> {
     /// ==============================================================================
     /// | SuperBlock | Bitmap (data region) | Bitmap (inodes) | Inodes | Data Region |
     /// ==============================================================================
-    pub fn create(mut dev: T) -> Result<Self, SFSError> {
+    pub fn create(dev: T) -> Result<Self, SFSError> {
+        SfsOptions::new().create(dev)
+    }
+
+    /// Formats `dev` for `SfsOptions::create`, which has already validated the options.
+    pub(crate) fn format(mut dev: T, options: SfsOptions) -> Result<Self, SFSError> {
+        // Formatting storage someone else has mounted would pull it out from under them.
+        lock_storage(&mut dev, true, options.force)?;
         // Reusable buffer for writing blocks.
         let mut block_buffer = [0; 4096];
 
-        // Init SuperBlock header.
-        let super_block = SuperBlock::default();
-        block_buffer[0..28].copy_from_slice(super_block.serialize());
-        dev.write_block(SUPERBLOCK_INDEX, &mut block_buffer)?;
+        // Init SuperBlock header, filling in both copies.
+        let mut super_block = SuperBlock::default();
+        super_block.write_copy(&mut block_buffer);
+        super_block.sequence += 1;
+        super_block.write_copy(&mut block_buffer);
+        dev.write_block(SUPERBLOCK_INDEX, &block_buffer)?;
 
         // Init allocation map for data region.
         let data_map = Bitmap::new();
-        block_buffer.copy_from_slice(data_map.serialize());
-        dev.write_block(DATA_REGION_BMP, &mut block_buffer)?;
+        block_buffer.copy_from_slice(&data_map.serialize());
+        dev.write_block(DATA_REGION_BMP, &block_buffer)?;
 
         // Initialize inode structure with root node.
         let inodes = InodeGroup::new(Bitmap::new());
-        block_buffer.copy_from_slice(inodes.allocations().serialize());
-        dev.write_block(INODE_BMP, &mut block_buffer)?;
-        dev.write_block(INODE_START, &mut inodes.serialize_block(0))?;
+        block_buffer.copy_from_slice(&inodes.allocations().serialize());
+        dev.write_block(INODE_BMP, &block_buffer)?;
+        dev.write_block(INODE_START, &inodes.serialize_block(0))?;
         dev.sync_disk()?;
 
-        Ok(SFS {
+        let mut fs = SFS {
             dev,
             inodes,
             data_map,
             super_block,
-        })
+            options: MountOptions::default(),
+            errored: Cell::new(false),
+            dcache: DentryCache::new(),
+            dirty: DirtyMetadata::default(),
+            io: IoStats::default(),
+            watchers: Watchers::default(),
+            txn: None,
+        };
+        fs.configure(options)?;
+        Ok(fs)
+    }
+
+    pub fn from_block_storage(dev: T) -> Result<Self, SFSError> {
+        SfsOptions::new().open(dev)
     }
 
-    pub fn from_block_storage(mut dev: T) -> Result<Self, SFSError> {
+    /// Reads the file system on `dev` for `SfsOptions::open`, which has already validated
+    /// the options.
+    pub(crate) fn load(mut dev: T, options: SfsOptions) -> Result<Self, SFSError> {
         let mut block_buf = vec![0; 4096];
 
         // Read superblock from first block;
         dev.read_block(SUPERBLOCK_INDEX, &mut block_buf)?;
-        let super_block = SuperBlock::parse(&block_buf, SB_MAGIC);
+        let mut super_block = SuperBlock::parse_block(&block_buf, SB_MAGIC)?;
+        // Images formatted before the name limit was recorded use the default limit.
+        if super_block.max_name_len == 0 {
+            super_block.max_name_len = MAX_NAME_LEN;
+        }
+        if let Some(block_count) = dev.block_count() {
+            let data_capacity = block_count.saturating_sub(DATA_START);
+            if super_block.blocks_count as usize > data_capacity {
+                return Err(SFSError::Corrupted(format!(
+                    "superblock describes {} data blocks but the device only holds {}",
+                    super_block.blocks_count, data_capacity
+                )));
+            }
+        }
+        if ErrorPolicy::from_raw(super_block.errors).is_none() {
+            return Err(SFSError::Corrupted(format!(
+                "unknown error policy {}",
+                super_block.errors
+            )));
+        }
+        if super_block.features & !SUPPORTED_FEATURES != 0 {
+            return Err(SFSError::Corrupted(format!(
+                "unknown feature flags {:#x}",
+                super_block.features & !SUPPORTED_FEATURES
+            )));
+        }
+        let inode_capacity = (DATA_START - INODE_START) * (BLOCK_SIZE / NODE_SIZE);
+        if super_block.inodes_count as usize > inode_capacity {
+            return Err(SFSError::Corrupted(format!(
+                "superblock describes {} inodes but the inode table only holds {}",
+                super_block.inodes_count, inode_capacity
+            )));
+        }
 
         dev.read_block(DATA_REGION_BMP, &mut block_buf)?;
-        let data_map = Bitmap::parse(&block_buf);
+        let data_map = Bitmap::parse(&block_buf)?;
 
         dev.read_block(INODE_BMP, &mut block_buf)?;
-        let inode_allocs = Bitmap::parse(&block_buf);
-        let mut inodes = InodeGroup::open(inode_allocs);
+        let inode_allocs = Bitmap::parse(&block_buf)?;
+        // Images formatted before inode numbers were reserved may already have given them to
+        // ordinary files, so only the root is special there.
+        let first_inode = if super_block.reserves_inodes() {
+            FIRST_INODE
+        } else {
+            ROOT_INODE + 1
+        };
+        let mut inodes = InodeGroup::open(inode_allocs, first_inode);
 
         for i in INODE_START..INODE_START + 5 {
             dev.read_block(i, &mut block_buf)?;
             // TODO(allancalix): This is a bit ugly. Because the inode group is unaware that's first
             // disk block is at an offset (INODE_START) we have to subtract the offset before loading
             // the block.
-            inodes.load_block((i - INODE_START) as u32, &block_buf);
+            inodes.load_block((i - INODE_START) as u32, &block_buf)?;
+        }
+        // The original format typed every inode but the root as a character device. It never
+        // wrote inodes back after formatting and mknod only came after the version was
+        // recorded, so an unversioned image holding a character device wasn't written here.
+        if super_block.is_unversioned() {
+            let chr = inodes
+                .iter()
+                .find(|(_, node)| node.mode() & FILE_TYPE_MASK == CHR_MODE);
+            if let Some((inum, _)) = chr {
+                return Err(SFSError::Corrupted(format!(
+                    "inode {} has the legacy character device type",
+                    inum
+                )));
+            }
         }
 
-        Ok(SFS {
+        let mut fs = SFS {
             dev,
             inodes,
             data_map,
             super_block,
-        })
+            options: MountOptions::default(),
+            errored: Cell::new(false),
+            dcache: DentryCache::new(),
+            dirty: DirtyMetadata::default(),
+            io: IoStats::default(),
+            watchers: Watchers::default(),
+            txn: None,
+        };
+        fs.configure(options)?;
+        Ok(fs)
     }
 
-    pub fn mkdir<P: AsRef<Path> + std::fmt::Display>(&mut self, path: P) -> Result<u32, SFSError> {
-        let parent_dir = path.as_ref().parent();
-        if parent_dir.is_none() {
-            return Err(SFSError::InvalidArgument(format!(
-                r#"could not parse parent directory from "{}""#,
-                path
-            )));
+    /// Opens an initialized file system the same way as `from_block_storage`, honoring the
+    /// provided mount options for the lifetime of the returned instance.
+    pub fn mount(dev: T, options: MountOptions) -> Result<Self, SFSError> {
+        SfsOptions::new().mount_options(options).open(dev)
+    }
+
+    /// Applies the options of a file system just created or opened by `SfsOptions` and locks
+    /// the storage, shared when mounted read-only and exclusive otherwise.
+    fn configure(&mut self, options: SfsOptions) -> Result<(), SFSError> {
+        self.options = options.mount;
+        self.options.read_only |= self.forced_read_only();
+        self.dcache = DentryCache::with_capacity(options.dcache_capacity);
+        lock_storage(&mut self.dev, !self.options.read_only, options.force)
+    }
+
+    /// Applies `opts` on top of the current mount options like `mount -o remount,...`, for
+    /// instance switching between read-only and read-write without unmounting. Buffered
+    /// changes are flushed first. Going read-write fails with `ReadOnly` and leaves the
+    /// options untouched for sealed images, storage that can't be written, and once
+    /// corruption was found under `ErrorPolicy::RemountReadOnly`.
+    pub fn remount(&mut self, opts: &str) -> Result<(), SFSError> {
+        self.check_outside_transaction()?;
+        let mut options = self.options.clone();
+        options.apply(opts)?;
+        if !options.read_only && (self.forced_read_only() || self.errored.get()) {
+            return Err(SFSError::ReadOnly);
+        }
+        // Writers need the storage to themselves, readers only keep writers out.
+        if !options.read_only {
+            if let Err(err) = lock_storage(&mut self.dev, true, false) {
+                // Converting a flock isn't atomic, the shared lock may be gone already.
+                let _ = self.dev.lock(false);
+                return Err(err);
+            }
         }
+        self.sync()?;
+        if options.read_only {
+            lock_storage(&mut self.dev, false, false)?;
+        }
+        self.options = options;
+        Ok(())
+    }
 
-        let filename = path.as_ref().file_name().unwrap();
-        let parent = self.open(parent_dir.unwrap(), OpenMode::RO)?;
-        let mut parent_content = self.read_dir(parent)?;
-        match parent_content.get(filename) {
-            // TODO(allancalix): Check spec as to whether this an error, noop, or what.
-            Some(_) => Err(SFSError::InvalidArgument("file already exists".to_string())),
-            None => {
-                let new_node = self.inodes.new_file();
-                parent_content.insert(OsString::from(filename), new_node);
-                self.write_dir(parent, parent_content)?;
+    /// Sealed images and storage that can't be written stay read-only no matter what was
+    /// asked for.
+    fn forced_read_only(&self) -> bool {
+        self.is_sealed() || self.dev.is_read_only()
+    }
+
+    /// Returns true if the image has been sealed with `seal`.
+    pub fn is_sealed(&self) -> bool {
+        self.super_block.is_sealed()
+    }
+
+    /// Seals the image for distribution: flushes everything, records a checksum of the whole
+    /// image in the superblock, and from then on the image can only be mounted read-only.
+    pub fn seal(&mut self) -> Result<(), SFSError> {
+        self.check_outside_transaction()?;
+        self.check_writable()?;
+        self.sync()?;
+        self.super_block.seal = self.image_checksum()?;
+        self.super_block.features |= SEALED_FEATURE;
+        self.write_super_block()?;
+        self.options.read_only = true;
+        Ok(())
+    }
+
+    /// Checks the image against the checksum recorded when it was sealed.
+    pub fn verify(&mut self) -> Result<(), SFSError> {
+        if !self.is_sealed() {
+            return Err(SFSError::InvalidArgument(
+                "image has not been sealed".to_string(),
+            ));
+        }
+        if self.image_checksum()? != self.super_block.seal {
+            return Err(SFSError::Corrupted(
+                "image contents don't match its seal".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Computes the CRC-32 of every block after the superblock up to the end of the data
+    /// region.
+    fn image_checksum(&mut self) -> Result<u32, SFSError> {
+        let mut block_buf = vec![0; BLOCK_SIZE];
+        let mut crc = 0;
+        for i in SUPERBLOCK_INDEX + 1..DATA_START + self.super_block.blocks_count as usize {
+            self.read_block(i, &mut block_buf)?;
+            crc = crc32(crc, &block_buf);
+        }
+        Ok(crc)
+    }
+
+    /// Returns the options the file system was mounted with.
+    pub fn mount_options(&self) -> &MountOptions {
+        &self.options
+    }
+
+    pub fn mkdir<P: AsRef<Path>>(&mut self, path: P) -> Result<u32, SFSError> {
+        let path = self.validate_path(path)?;
+        self.check_writable()?;
+        match self.resolve(&path)? {
+            (_, Some(_)) => Err(SFSError::Exists),
+            (parent, None) => {
+                self.check_mutable(parent)?;
+                let new_node = self.inodes.new_dir().ok_or(SFSError::NoSpace)?;
+                self.init_new_inode(parent, new_node, 0o777);
+                // Like ext4, case-insensitivity is inherited by new subdirectories.
+                if self.inodes.get(parent).unwrap().is_casefold() {
+                    self.inode_mut(new_node).flags |= CASEFOLD_FL;
+                }
+                self.append_dir_entry(parent, path.file_name().unwrap(), new_node)?;
+                self.commit()?;
+                self.notify(EventKind::Created, &path, new_node);
                 Ok(new_node)
             }
         }
     }
 
+    /// Creates a regular file, FIFO, socket, or character or block device like mknod(2).
+    /// `mode` holds the file type and the requested permission bits, `rdev` the device number
+    /// of a device file and is ignored for every other type.
+    pub fn mknod<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mode: u16,
+        rdev: u32,
+    ) -> Result<u32, SFSError> {
+        let file_type = mode & FILE_TYPE_MASK;
+        if !MKNOD_TYPES.contains(&file_type) {
+            return Err(SFSError::InvalidArgument(format!(
+                "mknod can't create file type {:#o}",
+                file_type
+            )));
+        }
+        // Unversioned images reject character devices on mount, see `load`.
+        if file_type == CHR_MODE && self.super_block.is_unversioned() {
+            return Err(SFSError::InvalidArgument(
+                "unversioned images can't hold character devices".to_string(),
+            ));
+        }
+        let path = self.validate_path(path)?;
+        self.check_writable()?;
+        let parent = match self.resolve(&path)? {
+            (_, Some(_)) => return Err(SFSError::Exists),
+            (parent, None) => parent,
+        };
+        self.check_mutable(parent)?;
+        let new_node = self
+            .inodes
+            .new_special(file_type, rdev)
+            .ok_or(SFSError::NoSpace)?;
+        self.init_new_inode(parent, new_node, mode);
+        self.append_dir_entry(parent, path.file_name().unwrap(), new_node)?;
+        self.commit()?;
+        self.notify(EventKind::Created, &path, new_node);
+        Ok(new_node)
+    }
+
     /// Opens a file descriptor at the path provided. By default, this implementation will return an
     /// error if the file does not exists. Set OpenMode to override the behavior and create a file or
     /// directory.
     pub fn open<P: AsRef<Path>>(&mut self, path: P, mode: OpenMode) -> Result<u32, SFSError> {
-        let mut parts = path.as_ref().components();
-        if Some(std::path::Component::RootDir) != parts.next() {
-            return Err(SFSError::InvalidArgument(
-                "path must start with \"/\"".to_string(),
-            ));
-        }
+        let path = self.validate_path(path)?;
+        self.open_path(&path, mode)
+    }
 
-        let mut inum = 0;
-        while let Some(part) = parts.next() {
-            let content = self.read_dir(inum)?;
-            let node = content.get(part.as_os_str());
-            if node.is_none() {
-                if parts.peekable().peek().is_some() {
-                    return Err(SFSError::InvalidArgument(
-                        "Missing subdirectory in path.".to_string(),
-                    ));
+    fn open_path(&mut self, path: &SfsPath, mode: OpenMode) -> Result<u32, SFSError> {
+        match (mode, self.resolve(path)?) {
+            // Like O_CREAT, creating a file that already exists opens it instead.
+            (OpenMode::CREATE, (_, Some(inum))) => {
+                if self.inodes.get(inum).unwrap().is_dir() {
+                    return Err(SFSError::IsADirectory);
+                }
+                Ok(inum)
+            }
+            (OpenMode::CREATE, (parent, None)) => {
+                self.check_writable()?;
+                self.check_mutable(parent)?;
+                let created_file = self.inodes.new_file().ok_or(SFSError::NoSpace)?;
+                self.init_new_inode(parent, created_file, 0o666);
+                self.append_dir_entry(parent, path.file_name().unwrap(), created_file)?;
+                self.commit()?;
+                self.notify(EventKind::Created, path, created_file);
+                Ok(created_file)
+            }
+            (OpenMode::RO, (_, Some(inum))) => Ok(inum),
+            // Like O_DIRECTORY, only directories can be opened.
+            (OpenMode::DIRECTORY, (_, Some(inum))) => {
+                if !self.inodes.get(inum).unwrap().is_dir() {
+                    return Err(SFSError::NotADirectory);
                 }
+                Ok(inum)
+            }
+            (OpenMode::RO, (_, None)) | (OpenMode::DIRECTORY, (_, None)) => {
+                Err(SFSError::DoesNotExist)
+            }
+            // Files can't be written to yet.
+            (OpenMode::WO, _) | (OpenMode::RW, _) => Err(SFSError::InvalidArgument(
+                "opening files for writing is not supported".to_string(),
+            )),
+        }
+    }
 
-                match mode {
-                    OpenMode::CREATE => break,
-                    _ => return Err(SFSError::DoesNotExist),
+    /// Walks `path` down to the directory holding its last component, returning that
+    /// directory and the inumber of the entry if it exists. Every directory along the way
+    /// must exist, and the root resolves to itself. This is the only place paths are walked.
+    fn resolve(&mut self, path: &SfsPath) -> Result<(u32, Option<u32>), SFSError> {
+        let mut parent = ROOT_INODE;
+        let mut entry = Some(ROOT_INODE);
+        for name in path.components() {
+            let dir = entry.ok_or(SFSError::DoesNotExist)?;
+            match self.inodes.get(dir) {
+                Some(node) if node.is_dir() => {}
+                Some(_) => return Err(SFSError::NotADirectory),
+                None => {
+                    return Err(self.report(SFSError::Corrupted(format!(
+                        "directory {} links to unallocated inode {}",
+                        parent, dir
+                    ))))
                 }
             }
+            parent = dir;
+            entry = self.lookup_cached(dir, OsStr::new(name))?;
+        }
+        Ok((parent, entry))
+    }
+
+    /// Maps logical block `block` of inode `inum` to the physical block holding it. Returns 0
+    /// for holes and for blocks past the last one an inode can address, like `bmap(2)`.
+    pub fn bmap(&self, inum: u32, block: usize) -> Result<u32, SFSError> {
+        // Validates every pointer so corruption is reported instead of handed to the caller.
+        self.data_blocks(inum)?;
+        let node = self.inodes.get(inum).unwrap();
+        Ok(node.blocks.get(block).copied().unwrap_or(0))
+    }
+
+    /// Returns the file type and permission bits of the file at `path`.
+    pub fn mode<P: AsRef<Path>>(&mut self, path: P) -> Result<u16, SFSError> {
+        let inum = self.open(path, OpenMode::RO)?;
+        Ok(self.inodes.get(inum).unwrap().mode())
+    }
+
+    /// Subscribes to changes made to the file at `path` and, if it's a directory, to its
+    /// entries or with `recursive` to everything beneath it. Events arrive on the returned
+    /// channel until it's dropped.
+    pub fn watch<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        recursive: bool,
+    ) -> Result<Receiver<Event>, SFSError> {
+        let path = self.validate_path(path)?;
+        self.open_path(&path, OpenMode::RO)?;
+        Ok(self.watchers.add(path.as_ref(), recursive))
+    }
 
-            inum = *node.unwrap();
+    fn notify(&mut self, kind: EventKind, path: &SfsPath, ino: u32) {
+        let event = Event {
+            kind,
+            path: path.to_path_buf(),
+            ino,
+        };
+        match &mut self.txn {
+            Some(txn) => txn.events.push(event),
+            None => self.watchers.notify(event),
         }
+    }
 
-        match mode {
-            OpenMode::CREATE => {
-                let created_file = self.inodes.new_file();
-                let mut parent_dir = self.read_dir(inum)?;
-                parent_dir.insert(
-                    OsString::from(path.as_ref().file_name().unwrap()),
-                    created_file,
-                );
-                self.write_dir(inum, parent_dir)?;
-                Ok(created_file)
-            }
-            OpenMode::RO => Ok(inum),
-            // The rest of the modes.
-            _ => unimplemented!(),
+    /// Returns a handle to the file at `path` that can later be opened with `open_ino`.
+    pub fn handle<P: AsRef<Path>>(&mut self, path: P) -> Result<FileHandle, SFSError> {
+        let ino = self.open(path, OpenMode::RO)?;
+        Ok(FileHandle {
+            ino,
+            generation: self.inodes.get(ino).unwrap().generation(),
+        })
+    }
+
+    /// Opens a file by inumber without resolving a path, returning the inumber back like
+    /// `open` does. Fails with `Stale` if the inode is no longer allocated or has been reused
+    /// since `generation` was handed out.
+    pub fn open_ino(&self, ino: u32, generation: u32) -> Result<u32, SFSError> {
+        match self.inodes.get(ino) {
+            Some(node) if node.generation() == generation => Ok(ino),
+            _ => Err(SFSError::Stale),
         }
     }
 
-    fn write_dir(&mut self, dir: u32, entries: HashMap<OsString, u32>) -> Result<(), SFSError> {
-        let mut contents: String = entries
-            .iter()
-            .map(|(k, v)| format!("{}:{}\n", v, k.to_str().unwrap()))
-            .collect();
-        contents.push('\0');
+    /// Returns the device number of the file at `path`, 0 unless it's a device file.
+    pub fn rdev<P: AsRef<Path>>(&mut self, path: P) -> Result<u32, SFSError> {
+        let inum = self.open(path, OpenMode::RO)?;
+        Ok(self.inodes.get(inum).unwrap().rdev())
+    }
 
-        let node = self.inodes.get_mut(dir).unwrap();
-        let allocated_blocks: Vec<u32> = node
-            .blocks
-            .iter()
-            .filter(|block| *block > &8_u32)
-            .copied()
-            .collect();
+    /// Returns the user and group owning the file at `path` as presented to callers, i.e.
+    /// with the `uid` and `gid` mount options taking precedence over the stored owner.
+    pub fn owner<P: AsRef<Path>>(&mut self, path: P) -> Result<(u32, u32), SFSError> {
+        let inum = self.open(path, OpenMode::RO)?;
+        let (uid, gid) = self.inodes.get(inum).unwrap().owner();
+        Ok((
+            self.options.uid.unwrap_or_else(|| uid.into()),
+            self.options.gid.unwrap_or_else(|| gid.into()),
+        ))
+    }
+
+    /// Returns the attribute flags of the file at `path`, see `IMMUTABLE_FL` and `APPEND_FL`.
+    pub fn flags<P: AsRef<Path>>(&mut self, path: P) -> Result<u32, SFSError> {
+        let inum = self.open(path, OpenMode::RO)?;
+        Ok(self.inodes.get(inum).unwrap().flags)
+    }
 
-        if allocated_blocks.len() < 1 + (contents.as_bytes().len() / BLOCK_SIZE) {
-            let needed = 1 + (contents.as_bytes().len() / BLOCK_SIZE);
-            let have = allocated_blocks.len();
-
-            let mut alloc_gen = NextAvailableAllocation::new(self.data_map, None);
-            let new_blocks: Vec<u32> = (0..(needed - have))
-                // Panics if no free blocks are available.
-                .map(|_| alloc_gen.next().unwrap() as u32)
-                // TODO(allancalix): 8 is hard-coded here are the start of the data region blocks.
-                .map(|v| v + 8)
-                .collect();
-            // Mark new blocks as allocated.
-            for &new_block in new_blocks.iter() {
-                self.data_map.set_reserved(new_block as usize);
-            }
-            let mut all_blocks = allocated_blocks.iter().chain(new_blocks.iter());
-            let new_blocks = all_blocks.clone().copied().collect::<Vec<u32>>();
-            node.blocks[0..new_blocks.len()].copy_from_slice(&new_blocks);
-
-            unsafe {
-                contents
-                    .as_bytes_mut()
-                    .chunks_mut(BLOCK_SIZE)
-                    .for_each(|chunk| {
-                        self.dev
-                            .write_block(*all_blocks.next().unwrap() as usize, chunk)
-                            .unwrap();
-                    });
+    /// Replaces the attribute flags of the file at `path`. Like chattr, this is allowed even
+    /// when the file is immutable so the flag can be cleared again.
+    pub fn set_flags<P: AsRef<Path>>(&mut self, path: P, flags: u32) -> Result<(), SFSError> {
+        self.check_writable()?;
+        if flags & !SUPPORTED_FLAGS != 0 {
+            return Err(SFSError::InvalidArgument(format!(
+                "unsupported attribute flags {:#x}",
+                flags & !SUPPORTED_FLAGS
+            )));
+        }
+        let path = self.validate_path(path)?;
+        let inum = self.open_path(&path, OpenMode::RO)?;
+        let node = self.inodes.get(inum).unwrap();
+        // Entries that only differ by case could collide once folding is switched on.
+        if (node.flags ^ flags) & CASEFOLD_FL != 0 {
+            if !node.is_dir() {
+                return Err(SFSError::NotADirectory);
+            }
+            if !self.read_dir(inum)?.is_empty() {
+                return Err(SFSError::NotEmpty);
             }
-            return Ok(());
         }
+        self.inode_mut(inum).flags = flags;
+        self.commit()?;
+        self.notify(EventKind::Modified, &path, inum);
+        Ok(())
+    }
 
-        info!("Writing content \"{}\" to dir inode {}.", contents, dir);
-        let mut blocks = allocated_blocks.iter();
-        unsafe {
-            contents
-                .as_bytes_mut()
-                .chunks_mut(BLOCK_SIZE)
-                .for_each(|chunk| {
-                    self.dev
-                        .write_block(*blocks.next().unwrap() as usize, chunk)
-                        .unwrap();
-                });
+    /// Grows or shrinks the data region to `blocks_count` blocks. Growing requires the
+    /// underlying device to report room for the new blocks, shrinking only succeeds if every
+    /// block being cut off is free.
+    pub fn resize(&mut self, blocks_count: u32) -> Result<(), SFSError> {
+        self.check_outside_transaction()?;
+        self.check_writable()?;
+        let new_count = blocks_count as usize;
+        if new_count > BITMAP_CAPACITY {
+            return Err(SFSError::InvalidArgument(format!(
+                "data region cannot exceed {} blocks",
+                BITMAP_CAPACITY
+            )));
+        }
+        let old_count = self.super_block.blocks_count as usize;
+        if new_count > old_count {
+            let available = self
+                .dev
+                .block_count()
+                .ok_or_else(|| {
+                    SFSError::InvalidArgument(
+                        "device size is unknown, the data region can't grow".to_string(),
+                    )
+                })?
+                .saturating_sub(DATA_START);
+            if new_count > available {
+                return Err(SFSError::InvalidArgument(format!(
+                    "device only has room for {} data blocks",
+                    available
+                )));
+            }
+        }
+        if new_count < old_count
+            && self.data_map.count(State::Used, old_count)
+                != self.data_map.count(State::Used, new_count)
+        {
+            return Err(SFSError::InvalidArgument(
+                "blocks beyond the new size are still in use".to_string(),
+            ));
         }
+
+        self.super_block.blocks_count = blocks_count;
+        self.write_super_block()?;
         Ok(())
     }
 
-    fn read_dir(&mut self, inum: u32) -> Result<HashMap<OsString, u32>, SFSError> {
-        let content = self.read_file(inum)?;
-        let contents_parsed = String::from_utf8(content).unwrap();
+    /// Validates a path passed to the public API, additionally rejecting components longer
+    /// than the file name limit recorded in the superblock.
+    fn validate_path<P: AsRef<Path>>(&self, path: P) -> Result<SfsPath, SFSError> {
+        let path = SfsPath::new(path)?;
+        if path
+            .components()
+            .any(|name| name.len() > self.super_block.max_name_len as usize)
+        {
+            return Err(SFSError::NameTooLong);
+        }
+        Ok(path)
+    }
 
-        let mut dir_contents = HashMap::new();
-        for line in contents_parsed.lines() {
-            if line.get(0..1) == Some("\0") {
-                break;
-            }
-            let mut contents = line.split(':');
-            let entry_inum = contents.next().unwrap().parse::<u32>().unwrap();
-            let entry_name = OsString::from(contents.next().unwrap());
-            dir_contents.insert(entry_name, entry_inum);
+    /// Sets up the mode and owner of an inode just created in directory `parent`, requesting
+    /// the `requested` permission bits the way open(2) and mkdir(2) callers do.
+    fn init_new_inode(&mut self, parent: u32, inum: u32, requested: u16) {
+        let parent = *self.inodes.get(parent).unwrap();
+        let MountOptions {
+            umask,
+            squash_uid,
+            squash_gid,
+            ..
+        } = self.options;
+        self.mark_dirty().inode_map = true;
+        let node = self.inode_mut(inum);
+        node.init_mode(requested, umask, &parent);
+        node.init_times(SystemTime::now());
+        // Squashing overrides any group inherited from a setgid parent.
+        let (uid, gid) = node.owner();
+        node.set_owner(squash_uid.unwrap_or(uid), squash_gid.unwrap_or(gid));
+    }
+
+    /// Returns a data block to the allocator, zeroing and discarding it first under
+    /// `secure_delete`.
+    fn free_block(&mut self, block: u32) -> Result<(), SFSError> {
+        if self.options.secure_delete {
+            self.write_block(block as usize, &[0; BLOCK_SIZE])?;
+            // Zeroing alone can leave the old contents in flash cells the device remapped.
+            self.dev.discard(block as usize)?;
         }
+        self.data_map.set_free(block as usize - DATA_START);
+        self.mark_dirty().data_map = true;
+        Ok(())
+    }
 
-        Ok(dir_contents)
+    /// Writes any buffered metadata changes to disk and syncs the device.
+    pub fn sync(&mut self) -> Result<(), SFSError> {
+        self.check_outside_transaction()?;
+        if self.dirty.since.is_none() {
+            return Ok(());
+        }
+        let dirty = std::mem::take(&mut self.dirty);
+        // Directory contents are written as they change, make sure they land before the
+        // inodes pointing at them.
+        self.dev.barrier()?;
+        if dirty.data_map {
+            let map = self.data_map.serialize();
+            self.write_block(DATA_REGION_BMP, &map)?;
+        }
+        if dirty.inode_map {
+            let map = self.inodes.allocations().serialize();
+            self.write_block(INODE_BMP, &map)?;
+        }
+        for block in dirty.inode_blocks {
+            let buf = self.inodes.serialize_block(block as u32);
+            self.write_block(INODE_START + block, &buf)?;
+        }
+        self.dev.sync_disk()?;
+        self.io.flushes += 1;
+        Ok(())
     }
 
-    fn read_file(&mut self, inum: u32) -> Result<Vec<u8>, SFSError> {
-        let node = self.inodes.get(inum);
-        if node.is_none() {
-            return Err(SFSError::DoesNotExist);
+    /// Runs `f` as a single transaction, all or nothing as far as this instance can tell: its
+    /// changes are undone in memory if it returns an error, and watchers only hear about them
+    /// once it returns Ok. On disk it only batches. The inode table and bitmaps changed by `f`
+    /// are written together in one flush at the end, but without a journal a crash during
+    /// that flush can leave part of the transaction on disk.
+    ///
+    /// Directory contents are written as `f` runs: adding an entry rewrites the directory's
+    /// last block and writes any blocks it grows into. Until the flush those bytes lie past
+    /// the size recorded on disk or in blocks still free on disk, so neither a crash nor a
+    /// rollback exposes them.
+    ///
+    /// Operations writing straight to disk (`sync`, `resize`, `defrag`, `seal` and
+    /// `set_error_policy`) fail inside a transaction, and a nested transaction simply becomes
+    /// part of the enclosing one.
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R, SFSError>
+    where
+        F: FnOnce(&mut Self) -> Result<R, SFSError>,
+    {
+        if self.txn.is_some() {
+            return f(self);
         }
-        let allocated_blocks: Vec<u32> = node
-            .unwrap()
-            .blocks
-            .iter()
-            // TODO(allancalix): Fix hard-coded data region start.
-            .filter(|block| **block >= 8_u32)
-            .copied()
-            .collect();
+        self.txn = Some(Transaction {
+            data_map: self.data_map,
+            inodes: self.inodes.clone(),
+            dirty: self.dirty.clone(),
+            events: vec![],
+        });
+        let result = f(self);
+        let txn = self.txn.take().unwrap();
+        match result {
+            Ok(value) => {
+                self.sync()?;
+                for event in txn.events {
+                    self.watchers.notify(event);
+                }
+                Ok(value)
+            }
+            Err(err) => {
+                // Directory entries are appended past the end of the old contents or into newly
+                // allocated blocks, so restoring the metadata is enough to forget them.
+                self.data_map = txn.data_map;
+                self.inodes = txn.inodes;
+                self.dirty = txn.dirty;
+                self.dcache.clear();
+                Err(err)
+            }
+        }
+    }
 
-        let mut content = vec![0; allocated_blocks.len() * BLOCK_SIZE];
-        for (i, &block) in allocated_blocks.iter().enumerate() {
-            let start = i * BLOCK_SIZE;
-            let end = start + BLOCK_SIZE;
-            self.dev
-                .read_block(block as usize, &mut content[start..end])?;
+    /// Rejects operations writing straight to disk, which a transaction couldn't undo.
+    fn check_outside_transaction(&self) -> Result<(), SFSError> {
+        if self.txn.is_some() {
+            return Err(SFSError::InvalidArgument(
+                "operation not allowed inside a transaction".to_string(),
+            ));
         }
-        Ok(content)
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::io::{FileBlockEmulator, FileBlockEmulatorBuilder};
+    /// Returns the block operations issued to the device since mounting.
+    pub fn io_stats(&self) -> IoStats {
+        self.io
+    }
 
-    fn create_test_device() -> FileBlockEmulator {
-        let dev = tempfile::tempfile().unwrap();
-        FileBlockEmulatorBuilder::from(dev)
-            .with_block_size(64)
-            .build()
-            .expect("Could not initialize disk emulator.")
+    /// Flushes buffered metadata when the sync policy says it is due, called at the end of
+    /// every modifying operation.
+    fn commit(&mut self) -> Result<(), SFSError> {
+        if self.txn.is_some() {
+            return Ok(());
+        }
+        match (self.options.sync, self.dirty.since) {
+            (SyncPolicy::Always, Some(_)) => self.sync(),
+            (SyncPolicy::Periodic(interval), Some(since)) if since.elapsed() >= interval => {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
     }
 
-    #[test]
-    fn root_dir_returns_root_fd() {
-        let dev = create_test_device();
-        let mut fs = SFS::create(dev).unwrap();
-        assert_eq!(fs.open("/", OpenMode::RO).unwrap(), 0);
+    /// Returns how long the flusher can sleep before buffered changes come due.
+    fn next_flush(&self) -> Duration {
+        let wait = match (self.options.sync, self.dirty.since) {
+            (SyncPolicy::Periodic(interval), Some(since)) => {
+                interval.saturating_sub(since.elapsed())
+            }
+            (SyncPolicy::Periodic(interval), None) => interval,
+            // Nothing comes due on its own, but the policy can change on remount.
+            _ => DEFAULT_COMMIT_INTERVAL,
+        };
+        wait.max(MIN_FLUSH_WAIT)
     }
 
-    #[test]
-    fn file_not_found_without_create_returns_error() {
-        let dev = create_test_device();
-        let mut fs = SFS::create(dev).unwrap();
+    fn mark_dirty(&mut self) -> &mut DirtyMetadata {
+        self.dirty.since.get_or_insert_with(Instant::now);
+        &mut self.dirty
+    }
 
-        let result = fs.open("/foo", OpenMode::RO);
-        match result.unwrap_err() {
-            SFSError::DoesNotExist => (),
-            _ => assert!(false, "Unexpected error type."),
-        }
+    /// Returns a mutable reference to an allocated inode, marking its block of the inode
+    /// table for writing on the next flush.
+    fn inode_mut(&mut self, inum: u32) -> &mut Inode {
+        self.mark_dirty()
+            .inode_blocks
+            .insert(inum as usize / NODES_PER_BLOCK);
+        self.inodes.get_mut(inum).unwrap()
     }
 
-    #[test]
-    fn create_non_existent_file_returns_handle() {
-        let dev = create_test_device();
+    fn read_block(&mut self, index: usize, buf: &mut [u8]) -> Result<(), SFSError> {
+        self.io.reads += 1;
+        self.dev.read_block(index, buf)?;
+        Ok(())
+    }
 
-        let mut fs = SFS::create(dev).unwrap();
+    fn write_block(&mut self, index: usize, buf: &[u8]) -> Result<(), SFSError> {
+        self.io.writes += 1;
+        self.dev.write_block(index, buf)?;
+        Ok(())
+    }
 
-        assert_eq!(fs.open("/foo", OpenMode::CREATE).unwrap(), 1);
+    /// Returns what the file system does after finding corruption while mounted, the
+    /// `errors=` mount option taking precedence over the superblock.
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.options.errors.unwrap_or_else(|| {
+            ErrorPolicy::from_raw(self.super_block.errors).unwrap_or(ErrorPolicy::Continue)
+        })
     }
 
-    #[test]
-    fn create_non_existent_file_with_missing_subdirectory_returns_error() {
-        let dev = create_test_device();
+    /// Changes the error policy recorded in the superblock. An `errors=` mount option still
+    /// takes precedence until unmounted.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) -> Result<(), SFSError> {
+        self.check_outside_transaction()?;
+        self.check_writable()?;
+        self.super_block.errors = policy.to_raw();
+        self.write_super_block()?;
+        Ok(())
+    }
 
-        let mut fs = SFS::create(dev).unwrap();
+    /// Applies the error policy to an error found while mounted and hands the error back
+    /// so the caller can return it. Only corruption triggers the policy.
+    fn report(&self, err: SFSError) -> SFSError {
+        if let SFSError::Corrupted(_) = err {
+            match self.error_policy() {
+                ErrorPolicy::Continue => error!("{}", err),
+                ErrorPolicy::RemountReadOnly => {
+                    error!("{}, rejecting further modifications", err);
+                    self.errored.set(true);
+                }
+                ErrorPolicy::Panic => panic!("{}", err),
+            }
+        }
+        err
+    }
 
-        assert!(fs.open("/foo/bar", OpenMode::CREATE).is_err());
+    fn check_writable(&self) -> Result<(), SFSError> {
+        if self.options.read_only || self.errored.get() {
+            return Err(SFSError::ReadOnly);
+        }
+        Ok(())
     }
 
-    #[test]
-    fn can_create_a_subdirectory() {
-        let dev = create_test_device();
-        let mut fs = SFS::create(dev).unwrap();
+    /// Looks up `name` in directory `dir`, only reading the directory if the dentry cache
+    /// doesn't already know the answer.
+    fn lookup_cached(&mut self, dir: u32, name: &OsStr) -> Result<Option<u32>, SFSError> {
+        if let Some(entry) = self.dcache.get(dir, name) {
+            return Ok(entry);
+        }
+        let content = self.read_dir(dir)?;
+        let entry = self.lookup(dir, &content, name);
+        self.dcache.insert(dir, name, entry);
+        Ok(entry)
+    }
 
-        assert_eq!(fs.mkdir("/foo").unwrap(), 1);
+    /// Returns hit and miss counts for the cache of directory lookups.
+    pub fn dcache_stats(&self) -> CacheStats {
+        self.dcache.stats()
     }
 
-    #[test]
-    fn can_open_an_existing_file_in_a_subdirectory() {
-        let dev = create_test_device();
-        let mut fs = SFS::create(dev).unwrap();
+    /// Finds `name` among the `entries` of directory `dir`, ignoring case if the directory
+    /// has the casefold attribute.
+    fn lookup(&self, dir: u32, entries: &HashMap<OsString, u32>, name: &OsStr) -> Option<u32> {
+        if let Some(&inum) = entries.get(name) {
+            return Some(inum);
+        }
+        if !self.inodes.get(dir)?.is_casefold() {
+            return None;
+        }
+        let folded = fold_case(name)?;
+        entries
+            .iter()
+            .find(|(entry, _)| fold_case(entry).as_ref() == Some(&folded))
+            .map(|(_, &inum)| inum)
+    }
 
-        fs.mkdir("/foo").unwrap();
-        fs.open("/foo/bar.txt", OpenMode::CREATE).unwrap();
+    fn check_mutable(&self, inum: u32) -> Result<(), SFSError> {
+        match self.inodes.get(inum) {
+            Some(node) if node.is_immutable() => Err(SFSError::NotPermitted),
+            _ => Ok(()),
+        }
+    }
 
-        assert_eq!(fs.open("/foo/bar.txt", OpenMode::RO).unwrap(), 3);
+    /// Writes the superblock straight through to stable storage, replacing the older of its
+    /// two copies so a torn write can't take out the other.
+    fn write_super_block(&mut self) -> Result<(), SFSError> {
+        let mut block_buffer = [0; BLOCK_SIZE];
+        self.read_block(SUPERBLOCK_INDEX, &mut block_buffer)?;
+        self.super_block.sequence = self.super_block.sequence.wrapping_add(1);
+        self.super_block.write_copy(&mut block_buffer);
+        self.io.writes += 1;
+        self.dev.write_block_fua(SUPERBLOCK_INDEX, &block_buffer)?;
+        Ok(())
     }
 
-    #[test]
-    fn mkdir_with_missing_subdirectory_returns_error() {
-        let dev = create_test_device();
-        let mut fs = SFS::create(dev).unwrap();
+    /// Reports block and inode usage for the whole file system.
+    pub fn stats(&self) -> FsStats {
+        let total_blocks = self.super_block.blocks_count as usize;
+        let used_blocks = self.data_map.count(State::Used, total_blocks);
+        let free_blocks = total_blocks - used_blocks;
+        let largest_free_extent = self
+            .data_map
+            .free_extents(total_blocks)
+            .iter()
+            .map(|extent| extent.len())
+            .max()
+            .unwrap_or(0);
+        let fragmentation = if free_blocks == 0 {
+            0.0
+        } else {
+            (free_blocks - largest_free_extent) as f64 * 100.0 / free_blocks as f64
+        };
 
-        assert!(fs.mkdir("/foo/bar").is_err());
+        let total_inodes = self.super_block.inodes_count as usize;
+        let allocations = self.inodes.allocations();
+        let used_inodes = allocations.count(State::Used, total_inodes);
+        // Reserved inode numbers can't be handed out, so they never count as free.
+        let reserved = (self.inodes.first_inode() as usize).min(total_inodes);
+        let free_inodes =
+            allocations.count(State::Free, total_inodes) - allocations.count(State::Free, reserved);
+
+        FsStats {
+            block_size: BLOCK_SIZE,
+            total_blocks,
+            used_blocks,
+            free_blocks,
+            total_inodes,
+            used_inodes,
+            free_inodes,
+            largest_free_extent,
+            fragmentation,
+            read_only: self.check_writable().is_err(),
+            atime: self.options.atime,
+        }
     }
 
-    #[test]
-    fn can_create_and_reopen_initialized_filesystem() {
+    /// Relocates data blocks so each inode's blocks occupy one contiguous run, ordered by
+    /// inumber, leaving all free space in a single extent at the end of the data region.
+    /// Every block is copied and its new location synced before the old one is reused, so
+    /// a crash part way through leaves every file intact. Fails with `NoSpace` if a block
+    /// has to be moved out of the way while there are no free blocks.
+    pub fn defrag(&mut self) -> Result<DefragReport, SFSError> {
+        self.check_outside_transaction()?;
+        self.check_writable()?;
+        let before = self.stats();
+
+        // Maps each allocated data block to the inode and block pointer referencing it, and
+        // lists the pointers in the order their blocks should end up on disk.
+        let mut owners: HashMap<u32, (u32, usize)> = HashMap::new();
+        let mut layout: Vec<(u32, usize)> = vec![];
+        for (inum, node) in self.inodes.iter() {
+            for (slot, &block) in node.blocks.iter().enumerate() {
+                if block >= DATA_START as u32 {
+                    owners.insert(block, (inum, slot));
+                    layout.push((inum, slot));
+                }
+            }
+        }
+
+        let mut blocks_moved = 0;
+        let mut buf = vec![0; BLOCK_SIZE];
+        for (i, &(inum, slot)) in layout.iter().enumerate() {
+            let target = (i + DATA_START) as u32;
+            let current = self.inodes.get(inum).unwrap().blocks[slot];
+            if current == target {
+                continue;
+            }
+
+            // Every block before the target is already in place, so a block occupying the
+            // target belongs further along in the layout. It's moved out of the way to a
+            // free block rather than swapped, so no block is overwritten while the pointers
+            // on disk still lead to it.
+            if let Some((other, other_slot)) = owners.remove(&target) {
+                let scratch = self.last_free_block().ok_or(SFSError::NoSpace)?;
+                self.relocate_block(other, other_slot, scratch, &mut buf)?;
+                owners.insert(scratch, (other, other_slot));
+                blocks_moved += 1;
+            }
+            owners.remove(&current);
+            self.relocate_block(inum, slot, target, &mut buf)?;
+            owners.insert(target, (inum, slot));
+            blocks_moved += 1;
+        }
+        // Writes out the last vacated block being freed.
+        self.sync()?;
+
+        Ok(DefragReport {
+            before,
+            after: self.stats(),
+            blocks_moved,
+        })
+    }
+
+    /// Copies the block behind pointer `slot` of `inum` to the free block `to`, points the
+    /// inode at the copy and frees the old block once the new pointer is on disk.
+    fn relocate_block(
+        &mut self,
+        inum: u32,
+        slot: usize,
+        to: u32,
+        buf: &mut [u8],
+    ) -> Result<(), SFSError> {
+        let from = self.inodes.get(inum).unwrap().blocks[slot];
+        self.read_block(from as usize, buf)?;
+        self.write_block(to as usize, buf)?;
+        self.data_map.set_reserved(to as usize - DATA_START);
+        self.inode_mut(inum).blocks[slot] = to;
+        self.mark_dirty().data_map = true;
+        // The barrier in sync puts the copy on disk before the pointer leading to it.
+        self.sync()?;
+        self.free_block(from)
+    }
+
+    /// Returns the free data block furthest into the data region, out of the way of blocks
+    /// being packed at its start.
+    fn last_free_block(&self) -> Option<u32> {
+        (0..self.super_block.blocks_count as usize)
+            .rev()
+            .find(|&block| self.data_map.get(block) == State::Free)
+            .map(|block| (block + DATA_START) as u32)
+    }
+
+    /// Walks the directory tree rooted at `path` and reports the data blocks used by
+    /// each directory, including everything beneath it. Entries are ordered the same
+    /// way `du` prints them, children before their parents.
+    pub fn dir_usage<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<DirUsage>, SFSError> {
+        let path = self.validate_path(path)?;
+        let inum = self.open_path(&path, OpenMode::RO)?;
+        let mut usage = vec![];
+        let mut visited = HashSet::new();
+        self.collect_usage(path.to_path_buf(), inum, &mut usage, &mut visited)?;
+        Ok(usage)
+    }
+
+    fn collect_usage(
+        &mut self,
+        path: PathBuf,
+        inum: u32,
+        usage: &mut Vec<DirUsage>,
+        visited: &mut HashSet<u32>,
+    ) -> Result<usize, SFSError> {
+        let mut blocks = self.data_blocks(inum)?.len();
+        if !self.inodes.get(inum).unwrap().is_dir() {
+            return Ok(blocks);
+        }
+        // Directories can't be hard linked, reaching one twice means the tree has a cycle.
+        if !visited.insert(inum) {
+            return Err(self.report(SFSError::Corrupted(format!(
+                "directory {} is linked more than once",
+                inum
+            ))));
+        }
+
+        let mut entries: Vec<(OsString, u32)> = self.read_dir(inum)?.into_iter().collect();
+        entries.sort();
+        for (name, child) in entries {
+            blocks += self.collect_usage(path.join(name), child, usage, visited)?;
+        }
+        usage.push(DirUsage { path, blocks });
+        Ok(blocks)
+    }
+
+    /// Renders the block layout as text, `BLOCK_MAP_WIDTH` blocks per line each prefixed
+    /// with the number of its first block. Metadata blocks show as `S` (superblock), `B`
+    /// (bitmaps) and `I` (inode table). Data blocks show the inumber owning them in base 36,
+    /// wrapping around after `z`, `.` when free, and `!` when allocated without an owner.
+    pub fn block_map(&self) -> String {
+        let owners = self.block_owners();
+        let mut map = String::new();
+        for block in 0..DATA_START + self.super_block.blocks_count as usize {
+            if block % BLOCK_MAP_WIDTH == 0 {
+                if block > 0 {
+                    map.push('\n');
+                }
+                map.push_str(&format!("{:>5}: ", block));
+            }
+            map.push(match block {
+                SUPERBLOCK_INDEX => 'S',
+                DATA_REGION_BMP | INODE_BMP => 'B',
+                _ if block < DATA_START => 'I',
+                _ => match owners.get(&(block as u32)) {
+                    Some(&inum) => std::char::from_digit(inum % 36, 36).unwrap(),
+                    None if self.data_map.get(block - DATA_START) == State::Used => '!',
+                    None => '.',
+                },
+            });
+        }
+        map.push('\n');
+        map
+    }
+
+    /// Dumps the directory tree in Graphviz dot format, with a node for every inode labeled
+    /// with its inumber and an edge for every directory entry labeled with its name.
+    pub fn tree_dot(&mut self) -> Result<String, SFSError> {
+        let mut dot = String::from("digraph sfs {\n");
+        let mut visited = HashSet::new();
+        self.collect_dot(ROOT_INODE, &mut dot, &mut visited)?;
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    fn collect_dot(
+        &mut self,
+        inum: u32,
+        dot: &mut String,
+        visited: &mut HashSet<u32>,
+    ) -> Result<(), SFSError> {
+        // Cycles are drawn as edges back to a node that's already been emitted.
+        if !visited.insert(inum) {
+            return Ok(());
+        }
+        let is_dir = self.inodes.get(inum).unwrap().is_dir();
+        let shape = if is_dir { "folder" } else { "note" };
+        dot.push_str(&format!(
+            "  n{} [label=\"{}\" shape={}];\n",
+            inum, inum, shape
+        ));
+        if !is_dir {
+            return Ok(());
+        }
+
+        let mut entries: Vec<(OsString, u32)> = self.read_dir(inum)?.into_iter().collect();
+        entries.sort();
+        for (name, child) in entries {
+            let label = name
+                .to_string_lossy()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"");
+            dot.push_str(&format!(
+                "  n{} -> n{} [label=\"{}\"];\n",
+                inum, child, label
+            ));
+            self.collect_dot(child, dot, visited)?;
+        }
+        Ok(())
+    }
+
+    /// Maps every data block pointed to by an inode to that inode.
+    fn block_owners(&self) -> HashMap<u32, u32> {
+        let mut owners = HashMap::new();
+        for (inum, node) in self.inodes.iter() {
+            for &block in node
+                .blocks
+                .iter()
+                .filter(|&&block| block >= DATA_START as u32)
+            {
+                owners.insert(block, inum);
+            }
+        }
+        owners
+    }
+
+    /// Returns the inode pointing to data block `block`, if any.
+    pub fn block_owner(&self, block: u32) -> Option<u32> {
+        self.block_owners().get(&block).copied()
+    }
+
+    /// Returns everything stored in inode `ino`, failing with `DoesNotExist` if it isn't
+    /// allocated.
+    pub fn istat(&self, ino: u32) -> Result<InodeStat, SFSError> {
+        let blocks = self.data_blocks(ino)?;
+        let node = self.inodes.get(ino).unwrap();
+        let (uid, gid) = node.owner();
+        Ok(InodeStat {
+            ino,
+            mode: node.mode(),
+            uid,
+            gid,
+            size: node.size,
+            flags: node.flags,
+            rdev: node.rdev(),
+            generation: node.generation(),
+            blocks,
+        })
+    }
+
+    /// Reads the contents of inode `ino` without going through a path, refreshing its access
+    /// time as the mount's `AtimePolicy` asks for.
+    pub fn read_inode(&mut self, ino: u32) -> Result<Vec<u8>, SFSError> {
+        let contents = self.read_file(ino)?;
+        self.touch_atime(ino)?;
+        Ok(contents)
+    }
+
+    /// Records an access to the contents of `inum` if the atime policy says the stored
+    /// access time is stale. Read-only mounts never update it.
+    fn touch_atime(&mut self, inum: u32) -> Result<(), SFSError> {
+        if self.check_writable().is_err() {
+            return Ok(());
+        }
+        let node = self.inodes.get(inum).unwrap();
+        let now = SystemTime::now();
+        // Inodes only keep one modification time, which stands in for the change time too.
+        if !self
+            .options
+            .atime
+            .should_update(node.atime(), node.mtime(), node.mtime(), now)
+        {
+            return Ok(());
+        }
+        self.inode_mut(inum).set_atime(now);
+        self.commit()
+    }
+
+    /// Walks the whole directory tree and returns every path leading to inode `ino`, sorted.
+    /// Orphaned inodes have none.
+    pub fn find_paths(&mut self, ino: u32) -> Result<Vec<PathBuf>, SFSError> {
+        let mut paths = vec![];
+        if ino == ROOT_INODE {
+            paths.push(PathBuf::from("/"));
+        }
+        let mut visited = HashSet::new();
+        self.collect_paths(
+            PathBuf::from("/"),
+            ROOT_INODE,
+            ino,
+            &mut paths,
+            &mut visited,
+        )?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn collect_paths(
+        &mut self,
+        path: PathBuf,
+        dir: u32,
+        ino: u32,
+        paths: &mut Vec<PathBuf>,
+        visited: &mut HashSet<u32>,
+    ) -> Result<(), SFSError> {
+        if !visited.insert(dir) {
+            return Ok(());
+        }
+        for (name, child) in self.read_dir(dir)? {
+            let child_path = path.join(name);
+            if child == ino {
+                paths.push(child_path.clone());
+            }
+            if self.inodes.get(child).is_some_and(Inode::is_dir) {
+                self.collect_paths(child_path, child, ino, paths, visited)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reconnects allocated inodes that no directory entry leads to under `/lost+found`,
+    /// naming each `#<inumber>` like e2fsck does, and returns their inumbers. Orphaned
+    /// directories bring everything beneath them along. The directory is created the first
+    /// time it's needed, with the reserved `LOST_FOUND_INODE` on images that reserve it.
+    pub fn adopt_orphans(&mut self) -> Result<Vec<u32>, SFSError> {
+        self.check_writable()?;
+        let mut reached = HashSet::new();
+        self.mark_reachable(ROOT_INODE, &mut reached)?;
+        let orphans: Vec<u32> = self
+            .inodes
+            .iter()
+            .map(|(inum, _)| inum)
+            .filter(|&inum| inum >= self.inodes.first_inode() && !reached.contains(&inum))
+            .collect();
+        if orphans.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let lost_found = self.lost_found(&reached)?;
+        self.mark_reachable(lost_found, &mut reached)?;
+        // Orphans found inside other orphaned directories come along with them, only the
+        // tops of orphaned trees are reconnected unless they form a cycle.
+        let mut claimed = HashSet::new();
+        for &inum in &orphans {
+            if self.inodes.get(inum).unwrap().is_dir() {
+                claimed.extend(self.read_dir(inum)?.into_values());
+            }
+        }
+        let (unclaimed, claimed): (Vec<u32>, Vec<u32>) = orphans
+            .into_iter()
+            .partition(|inum| !claimed.contains(inum));
+
+        let mut adopted = vec![];
+        for inum in unclaimed.into_iter().chain(claimed) {
+            if reached.contains(&inum) {
+                continue;
+            }
+            let name = format!("#{}", inum);
+            if self.lookup_cached(lost_found, OsStr::new(&name))?.is_some() {
+                return Err(SFSError::Exists);
+            }
+            self.append_dir_entry(lost_found, &name, inum)?;
+            self.mark_reachable(inum, &mut reached)?;
+            adopted.push(inum);
+        }
+        self.commit()?;
+        Ok(adopted)
+    }
+
+    /// Returns the `/lost+found` directory, creating it or linking the reserved inode back
+    /// into the root if it's missing. `reached` holds every inode linked into the tree.
+    fn lost_found(&mut self, reached: &HashSet<u32>) -> Result<u32, SFSError> {
+        if let Some(inum) = self.lookup_cached(ROOT_INODE, OsStr::new(LOST_FOUND_NAME))? {
+            if !self.inodes.get(inum).unwrap().is_dir() {
+                return Err(SFSError::NotADirectory);
+            }
+            return Ok(inum);
+        }
+        let created = if self.inodes.reserves_inodes() {
+            self.inodes.new_lost_found()
+        } else {
+            // Older images may have handed the reserved inode out to an ordinary file.
+            Some(self.inodes.new_dir().ok_or(SFSError::NoSpace)?)
+        };
+        let inum = match created {
+            Some(inum) => {
+                // Only root should be poking around in files that lost their names.
+                self.init_new_inode(ROOT_INODE, inum, 0o700);
+                inum
+            }
+            // The reserved directory only lost its name, unless something else holds it.
+            None => {
+                let linked = reached.contains(&LOST_FOUND_INODE);
+                if linked || !self.inodes.get(LOST_FOUND_INODE).unwrap().is_dir() {
+                    return Err(self.report(SFSError::Corrupted(format!(
+                        "reserved inode {} is not an unlinked directory",
+                        LOST_FOUND_INODE
+                    ))));
+                }
+                LOST_FOUND_INODE
+            }
+        };
+        self.append_dir_entry(ROOT_INODE, LOST_FOUND_NAME, inum)?;
+        self.notify(EventKind::Created, &SfsPath::new("/lost+found")?, inum);
+        Ok(inum)
+    }
+
+    /// Adds `inum` and everything beneath it to `reached`.
+    fn mark_reachable(&mut self, inum: u32, reached: &mut HashSet<u32>) -> Result<(), SFSError> {
+        if !reached.insert(inum) || !self.inodes.get(inum).is_some_and(Inode::is_dir) {
+            return Ok(());
+        }
+        for child in self.read_dir(inum)?.into_values() {
+            self.mark_reachable(child, reached)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a single entry to the end of directory `dir`. Only the blocks the new entry lands
+    /// in are written, so adding an entry costs the same regardless of the directory's size.
+    fn append_dir_entry(&mut self, dir: u32, name: &str, inum: u32) -> Result<(), SFSError> {
+        self.dcache.invalidate_dir(dir);
+        let entry = format!("{}:{}\n", inum, name);
+        let size = self.inodes.get(dir).unwrap().size as usize;
+        let new_size = size + entry.len();
+
+        let mut blocks = self.data_blocks(dir)?;
+        self.allocate_blocks(&mut blocks, new_size.div_ceil(BLOCK_SIZE))?;
+
+        // The entry starts in the last block holding data, which has to be rewritten with
+        // its existing contents intact.
+        let first = size / BLOCK_SIZE;
+        let mut tail = vec![0; BLOCK_SIZE];
+        if !size.is_multiple_of(BLOCK_SIZE) {
+            self.read_block(blocks[first] as usize, &mut tail)?;
+        }
+        tail.truncate(size % BLOCK_SIZE);
+        tail.extend_from_slice(entry.as_bytes());
+
+        let node = self.inode_mut(dir);
+        node.blocks[0..blocks.len()].copy_from_slice(&blocks);
+        node.size = new_size as u32;
+        node.set_mtime(SystemTime::now());
+
+        self.write_blocks(&blocks[first..], &tail)
+    }
+
+    /// Allocates data blocks onto the end of `blocks` until it holds `count` of them.
+    fn allocate_blocks(&mut self, blocks: &mut Vec<u32>, count: usize) -> Result<(), SFSError> {
+        if count > DIRECT_BLOCKS {
+            return Err(SFSError::NoSpace);
+        }
+        if blocks.len() >= count {
+            return Ok(());
+        }
+
+        let mut alloc_gen = NextAvailableAllocation::new(
+            self.data_map,
+            Some(self.super_block.blocks_count as usize),
+        );
+        let new_blocks: Vec<usize> = (0..(count - blocks.len()))
+            .map(|_| alloc_gen.next().ok_or(SFSError::NoSpace))
+            .collect::<Result<_, _>>()?;
+        // Mark new blocks as allocated, the data bitmap is indexed relative to the start of
+        // the data region.
+        for &new_block in new_blocks.iter() {
+            self.data_map.set_reserved(new_block);
+        }
+        self.mark_dirty().data_map = true;
+        blocks.extend(new_blocks.iter().map(|&v| (v + DATA_START) as u32));
+        Ok(())
+    }
+
+    /// Writes `contents` across `blocks` in order. The final block is padded with zeros rather
+    /// than whatever the block previously held.
+    fn write_blocks(&mut self, blocks: &[u32], contents: &[u8]) -> Result<(), SFSError> {
+        let mut block_buf = vec![0; BLOCK_SIZE];
+        for (chunk, &block) in contents.chunks(BLOCK_SIZE).zip(blocks) {
+            block_buf[..chunk.len()].copy_from_slice(chunk);
+            block_buf[chunk.len()..].iter_mut().for_each(|b| *b = 0);
+            self.write_block(block as usize, &block_buf)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&mut self, inum: u32) -> Result<HashMap<OsString, u32>, SFSError> {
+        match self.inodes.get(inum) {
+            Some(node) if !node.is_dir() => return Err(SFSError::NotADirectory),
+            _ => (),
+        }
+        let content = self.read_file(inum)?;
+        parse_dir(&content).map_err(|err| self.report(err))
+    }
+
+    /// Reads the contents of an inode, returning exactly as many bytes as its recorded size.
+    fn read_file(&mut self, inum: u32) -> Result<Vec<u8>, SFSError> {
+        let allocated_blocks = self.data_blocks(inum)?;
+        let size = self.inodes.get(inum).unwrap().size as usize;
+        if size > allocated_blocks.len() * BLOCK_SIZE {
+            return Err(self.report(SFSError::Corrupted(format!(
+                "inode {} size exceeds its allocated blocks",
+                inum
+            ))));
+        }
+
+        let mut content = vec![0; allocated_blocks.len() * BLOCK_SIZE];
+        for (i, &block) in allocated_blocks.iter().enumerate() {
+            let start = i * BLOCK_SIZE;
+            let end = start + BLOCK_SIZE;
+            self.read_block(block as usize, &mut content[start..end])?;
+        }
+        content.truncate(size);
+        Ok(content)
+    }
+
+    /// Returns the data blocks an inode points to, rejecting pointers that fall outside of the
+    /// data region.
+    fn data_blocks(&self, inum: u32) -> Result<Vec<u32>, SFSError> {
+        let node = self.inodes.get(inum).ok_or(SFSError::DoesNotExist)?;
+        let data_end = (DATA_START + self.super_block.blocks_count as usize) as u32;
+        node.blocks
+            .iter()
+            .filter(|&&block| block != 0)
+            .map(|&block| {
+                if block < DATA_START as u32 || block >= data_end {
+                    return Err(self.report(SFSError::Corrupted(format!(
+                        "inode {} points to block {} outside of the data region",
+                        inum, block
+                    ))));
+                }
+                Ok(block)
+            })
+            .collect()
+    }
+}
+
+/// Takes the advisory lock on `dev` that lets any number of read-only mounts share the
+/// storage but gives a read-write mount sole use of it. With `force` a conflicting lock is
+/// only logged.
+fn lock_storage<T: BlockStorage>(
+    dev: &mut T,
+    exclusive: bool,
+    force: bool,
+) -> Result<(), SFSError> {
+    match dev.lock(exclusive) {
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            if !force {
+                return Err(SFSError::Busy);
+            }
+            warn!("storage is locked by another mount, continuing anyway");
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Folds a name for case-insensitive comparison using Unicode lowercase mappings. No other
+/// normalization is applied, and names that aren't valid UTF-8 only match exactly.
+fn fold_case(name: &OsStr) -> Option<String> {
+    name.to_str().map(str::to_lowercase)
+}
+
+/// Parses the raw contents of a directory, stored as one `<inumber>:<name>` entry per line.
+pub(crate) fn parse_dir(content: &[u8]) -> Result<HashMap<OsString, u32>, SFSError> {
+    let contents_parsed = std::str::from_utf8(content)
+        .map_err(|_| SFSError::Corrupted("directory is not valid UTF-8".to_string()))?;
+
+    let mut dir_contents = HashMap::new();
+    for line in contents_parsed.lines() {
+        let mut contents = line.splitn(2, ':');
+        let entry_inum = contents.next().and_then(|v| v.parse::<u32>().ok());
+        match (entry_inum, contents.next()) {
+            (Some(entry_inum), Some(entry_name)) => {
+                dir_contents.insert(OsString::from(entry_name), entry_inum);
+            }
+            _ => return Err(SFSError::Corrupted("malformed directory entry".to_string())),
+        }
+    }
+
+    Ok(dir_contents)
+}
+
+impl<T: BlockStorage + Send + 'static> SFS<T> {
+    /// Starts a thread that writes out buffered metadata as soon as it comes due under
+    /// `SyncPolicy::Periodic`, instead of waiting for the next modifying operation to notice.
+    /// The thread exits once every other reference to the file system has been dropped.
+    pub fn spawn_flusher(fs: &Arc<Mutex<Self>>) -> JoinHandle<()> {
+        let fs = Arc::downgrade(fs);
+        thread::spawn(move || loop {
+            let wait = match fs.upgrade().as_deref().map(Mutex::lock) {
+                Some(Ok(mut fs)) => {
+                    if let Err(e) = fs.commit() {
+                        error!("failed to flush metadata: {}", e);
+                    }
+                    fs.next_flush()
+                }
+                // Unmounted, or another thread panicked while holding the lock.
+                _ => return,
+            };
+            thread::sleep(wait);
+        })
+    }
+}
+
+impl<T: BlockStorage> Drop for SFS<T> {
+    fn drop(&mut self) {
+        // Metadata is left alone once corruption has been found, same as other writes, and
+        // when unwinding out of a transaction that never finished.
+        if self.errored.get() || self.txn.is_some() {
+            return;
+        }
+        if let Err(e) = self.sync() {
+            error!("failed to flush metadata on unmount: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{FileBlockEmulator, FileBlockEmulatorBuilder};
+    use crate::node::{APPEND_FL, CASEFOLD_FL, IMMUTABLE_FL};
+    use crate::path::MAX_PATH_DEPTH;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn create_test_device() -> FileBlockEmulator {
+        let dev = tempfile::tempfile().unwrap();
+        FileBlockEmulatorBuilder::from(dev)
+            .with_block_size(64)
+            .build()
+            .expect("Could not initialize disk emulator.")
+    }
+
+    /// Expands a golden image, stored as `<offset>: <hex words>` rows with the all zero rows
+    /// left out, into the contents of a 64 block device.
+    fn golden_image(dump: &str) -> Vec<u8> {
+        let mut image = vec![0; 64 * BLOCK_SIZE];
+        for line in dump.lines().filter(|line| !line.starts_with('#')) {
+            let mut parts = line.splitn(2, ": ");
+            let offset = usize::from_str_radix(parts.next().unwrap(), 16).unwrap();
+            let hex: String = parts.next().unwrap().split_whitespace().collect();
+            for i in 0..hex.len() / 2 {
+                image[offset + i] = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+            }
+        }
+        image
+    }
+
+    fn golden_device(dump: &str) -> FileBlockEmulator {
+        let mut dev = create_test_device();
+        for (i, block) in golden_image(dump).chunks(BLOCK_SIZE).enumerate() {
+            dev.write_block(i, block).unwrap();
+        }
+        dev
+    }
+
+    #[test]
+    fn root_dir_returns_root_fd() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        assert_eq!(fs.open("/", OpenMode::RO).unwrap(), 0);
+    }
+
+    #[test]
+    fn unsupported_open_modes_return_errors() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let dir = fs.mkdir("/dir").unwrap();
+        fs.open("/file", OpenMode::CREATE).unwrap();
+
+        for mode in [OpenMode::WO, OpenMode::RW] {
+            let err = fs.open("/file", mode).unwrap_err();
+            assert_eq!(err.to_errno(), libc::EINVAL);
+        }
+        assert_eq!(fs.open("/dir", OpenMode::DIRECTORY).unwrap(), dir);
+        for (path, errno) in [("/file", libc::ENOTDIR), ("/missing", libc::ENOENT)] {
+            let err = fs.open(path, OpenMode::DIRECTORY).unwrap_err();
+            assert_eq!(err.to_errno(), errno);
+        }
+    }
+
+    #[test]
+    fn file_not_found_without_create_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        let result = fs.open("/foo", OpenMode::RO);
+        match result.unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn create_non_existent_file_returns_handle() {
+        let dev = create_test_device();
+
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert_eq!(fs.open("/foo", OpenMode::CREATE).unwrap(), FIRST_INODE);
+    }
+
+    #[test]
+    fn squash_options_set_owner_of_new_files() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.options = "squash_uid=1000,squash_gid=100".parse().unwrap();
+
+        fs.mkdir("/foo").unwrap();
+        fs.open("/foo/bar.txt", OpenMode::CREATE).unwrap();
+
+        assert_eq!(fs.owner("/").unwrap(), (0, 0));
+        assert_eq!(fs.owner("/foo").unwrap(), (1000, 100));
+        assert_eq!(fs.owner("/foo/bar.txt").unwrap(), (1000, 100));
+    }
+
+    #[test]
+    fn new_files_use_mount_umask() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.options = "umask=027".parse().unwrap();
+
+        fs.mkdir("/foo").unwrap();
+        fs.open("/foo/bar.txt", OpenMode::CREATE).unwrap();
+
+        assert_eq!(fs.mode("/foo").unwrap(), 0o40750);
+        assert_eq!(fs.mode("/foo/bar.txt").unwrap(), 0o100640);
+    }
+
+    #[test]
+    fn watchers_receive_events_for_changes() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let root = fs.watch("/", false).unwrap();
+        let foo = fs.mkdir("/foo").unwrap();
+        let tree = fs.watch("/foo", true).unwrap();
+
+        let bar = fs.mkdir("/foo/bar").unwrap();
+        let baz = fs.open("/foo/bar/baz.txt", OpenMode::CREATE).unwrap();
+        fs.set_flags("/foo", IMMUTABLE_FL).unwrap();
+
+        let event = |kind, path: &str, ino| Event {
+            kind,
+            path: PathBuf::from(path),
+            ino,
+        };
+        assert_eq!(
+            root.try_iter().collect::<Vec<_>>(),
+            vec![
+                event(EventKind::Created, "/foo", foo),
+                event(EventKind::Modified, "/foo", foo),
+            ]
+        );
+        assert_eq!(
+            tree.try_iter().collect::<Vec<_>>(),
+            vec![
+                event(EventKind::Created, "/foo/bar", bar),
+                event(EventKind::Created, "/foo/bar/baz.txt", baz),
+                event(EventKind::Modified, "/foo", foo),
+            ]
+        );
+        assert!(fs.watch("/missing", false).is_err());
+    }
+
+    #[test]
+    fn mknod_creates_special_files() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/dev").unwrap();
+
+        fs.mknod("/dev/null", libc::S_IFCHR as u16 | 0o666, 0x0103)
+            .unwrap();
+        fs.mknod("/dev/pipe", libc::S_IFIFO as u16 | 0o644, 0x0103)
+            .unwrap();
+        fs.mknod("/dev/sock", libc::S_IFSOCK as u16 | 0o777, 0)
+            .unwrap();
+
+        assert_eq!(fs.mode("/dev/null").unwrap(), libc::S_IFCHR as u16 | 0o644);
+        assert_eq!(fs.rdev("/dev/null").unwrap(), 0x0103);
+        assert_eq!(fs.mode("/dev/pipe").unwrap(), libc::S_IFIFO as u16 | 0o644);
+        assert_eq!(fs.rdev("/dev/pipe").unwrap(), 0);
+        assert_eq!(fs.mode("/dev/sock").unwrap(), libc::S_IFSOCK as u16 | 0o755);
+    }
+
+    #[test]
+    fn mknod_rejects_directories_and_existing_names() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        match fs
+            .mknod("/bar", libc::S_IFDIR as u16 | 0o755, 0)
+            .unwrap_err()
+        {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs
+            .mknod("/foo", libc::S_IFIFO as u16 | 0o644, 0)
+            .unwrap_err()
+        {
+            SFSError::Exists => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn owner_options_override_stored_owner() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.options = "uid=501".parse().unwrap();
+
+        assert_eq!(fs.owner("/foo").unwrap(), (501, 0));
+    }
+
+    #[test]
+    fn immutable_directory_rejects_new_entries() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.set_flags("/foo", IMMUTABLE_FL).unwrap();
+
+        assert_eq!(fs.flags("/foo").unwrap(), IMMUTABLE_FL);
+        match fs.mkdir("/foo/bar").unwrap_err() {
+            SFSError::NotPermitted => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.open("/foo/baz.txt", OpenMode::CREATE).unwrap_err() {
+            SFSError::NotPermitted => (),
+            _ => panic!("Unexpected error type."),
+        }
+
+        fs.set_flags("/foo", 0).unwrap();
+        assert!(fs.mkdir("/foo/bar").is_ok());
+    }
+
+    #[test]
+    fn setting_unsupported_flags_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert!(fs.set_flags("/", APPEND_FL).is_ok());
+        match fs.set_flags("/", 0x1).unwrap_err() {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn casefold_directory_ignores_case_on_lookup() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/docs").unwrap();
+        fs.set_flags("/docs", CASEFOLD_FL).unwrap();
+
+        let dir = fs.mkdir("/docs/Drafts").unwrap();
+        let file = fs.open("/docs/Notes.txt", OpenMode::CREATE).unwrap();
+
+        assert_eq!(fs.open("/docs/DRAFTS", OpenMode::RO).unwrap(), dir);
+        assert_eq!(fs.open("/docs/notes.TXT", OpenMode::CREATE).unwrap(), file);
+        match fs.mkdir("/docs/drafts").unwrap_err() {
+            SFSError::Exists => (),
+            _ => panic!("Unexpected error type."),
+        }
+        // The original spelling is preserved and subdirectories inherit the flag.
+        let docs = fs.open("/docs", OpenMode::RO).unwrap();
+        assert!(fs
+            .read_dir(docs)
+            .unwrap()
+            .contains_key(OsStr::new("Drafts")));
+        assert_eq!(fs.flags("/docs/drafts").unwrap(), CASEFOLD_FL);
+        // Directories without the flag still match exactly.
+        assert!(fs.open("/DOCS", OpenMode::RO).is_err());
+    }
+
+    #[test]
+    fn casefold_can_only_change_on_empty_directories() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo.txt", OpenMode::CREATE).unwrap();
+
+        match fs.set_flags("/", CASEFOLD_FL).unwrap_err() {
+            SFSError::NotEmpty => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.set_flags("/foo.txt", CASEFOLD_FL).unwrap_err() {
+            SFSError::NotADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn new_entries_are_appended_to_directory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.open("/a.txt", OpenMode::CREATE).unwrap();
+        fs.mkdir("/c").unwrap();
+
+        assert_eq!(fs.read_file(0).unwrap(), b"5:b\n6:a.txt\n7:c\n");
+    }
+
+    #[test]
+    fn appended_entries_can_span_blocks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let name = "f".repeat(200);
+        for i in 0..30 {
+            fs.open(format!("/{}{}", name, i), OpenMode::CREATE)
+                .unwrap();
+        }
+
+        assert_eq!(fs.data_blocks(0).unwrap().len(), 2);
+        let entries = fs.read_dir(0).unwrap();
+        assert_eq!(entries.len(), 30);
+        for i in 0..30 {
+            let inum = entries[OsStr::new(&format!("{}{}", name, i))];
+            assert_eq!(
+                fs.open(format!("/{}{}", name, i), OpenMode::RO).unwrap(),
+                inum
+            );
+        }
+    }
+
+    #[test]
+    fn directories_cannot_outgrow_direct_blocks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        match fs
+            .allocate_blocks(&mut vec![], DIRECT_BLOCKS + 1)
+            .unwrap_err()
+        {
+            SFSError::NoSpace => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn repeated_lookups_are_served_from_dcache() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let bar = fs.mkdir("/foo").and_then(|_| fs.mkdir("/foo/bar")).unwrap();
+
+        assert_eq!(fs.open("/foo/bar", OpenMode::RO).unwrap(), bar);
+        let before = fs.dcache_stats();
+        assert_eq!(fs.open("/foo/bar", OpenMode::RO).unwrap(), bar);
+
+        let after = fs.dcache_stats();
+        assert_eq!(after.misses, before.misses);
+        assert_eq!(after.hits, before.hits + 2);
+    }
+
+    #[test]
+    fn dcache_negative_entries_are_invalidated_on_create() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert!(fs.open("/foo", OpenMode::RO).is_err());
+        assert!(fs.open("/foo", OpenMode::RO).is_err());
+        assert_eq!(fs.dcache_stats().hits, 1);
+
+        let foo = fs.mkdir("/foo").unwrap();
+        assert_eq!(fs.open("/foo", OpenMode::RO).unwrap(), foo);
+        let file = fs.open("/foo/bar.txt", OpenMode::CREATE).unwrap();
+        assert_eq!(fs.open("/foo/bar.txt", OpenMode::RO).unwrap(), file);
+    }
+
+    #[test]
+    fn create_existing_file_returns_same_handle() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        let file = fs.open("/foo", OpenMode::CREATE).unwrap();
+        fs.mkdir("/bar").unwrap();
+
+        assert_eq!(fs.open("/foo", OpenMode::CREATE).unwrap(), file);
+        assert_eq!(fs.read_dir(0).unwrap().len(), 2);
+        match fs.open("/bar", OpenMode::CREATE).unwrap_err() {
+            SFSError::IsADirectory => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn create_non_existent_file_with_missing_subdirectory_returns_error() {
+        let dev = create_test_device();
+
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert!(fs.open("/foo/bar", OpenMode::CREATE).is_err());
+    }
+
+    #[test]
+    fn can_create_a_subdirectory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert_eq!(fs.mkdir("/foo").unwrap(), FIRST_INODE);
+    }
+
+    #[test]
+    fn can_open_an_existing_file_in_a_subdirectory() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        fs.mkdir("/foo").unwrap();
+        fs.open("/foo/bar.txt", OpenMode::CREATE).unwrap();
+
+        assert_eq!(
+            fs.open("/foo/bar.txt", OpenMode::RO).unwrap(),
+            FIRST_INODE + 1
+        );
+    }
+
+    #[test]
+    fn mkdir_with_missing_subdirectory_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert!(fs.mkdir("/foo/bar").is_err());
+    }
+
+    #[test]
+    fn can_create_and_reopen_initialized_filesystem() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        // Initialize the filesystem.
+        SFS::create(dev).unwrap();
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            // Don't reset initialized disk.
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let fs: SFS<FileBlockEmulator> = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(fs.inodes.total_nodes(), 1);
+    }
+
+    #[test]
+    fn buffered_metadata_is_flushed_on_drop() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.open("/foo/bar.txt", OpenMode::CREATE).unwrap();
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(
+            fs.open("/foo/bar.txt", OpenMode::RO).unwrap(),
+            FIRST_INODE + 1
+        );
+        assert_eq!(fs.inodes.total_nodes(), 3);
+    }
+
+    #[test]
+    fn file_handles_survive_remount() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        let handle = fs.handle("/foo").unwrap();
+        let exported = handle.to_bytes();
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let fs = SFS::from_block_storage(dev).unwrap();
+        let handle = FileHandle::from_bytes(exported);
+        assert_eq!(
+            fs.open_ino(handle.ino, handle.generation).unwrap(),
+            handle.ino
+        );
+    }
+
+    #[test]
+    fn open_ino_rejects_stale_handles() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+        let handle = fs.handle("/foo").unwrap();
+
+        match fs.open_ino(handle.ino, handle.generation.wrapping_add(1)) {
+            Err(SFSError::Stale) => (),
+            _ => panic!("Expected a stale handle error."),
+        }
+        match fs.open_ino(handle.ino + 1, handle.generation) {
+            Err(SFSError::Stale) => (),
+            _ => panic!("Expected a stale handle error."),
+        }
+    }
+
+    #[test]
+    fn sealed_images_are_read_only_and_verifiable() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.seal().unwrap();
+
+        assert_eq!(fs.mkdir("/bar").unwrap_err().to_errno(), libc::EROFS);
+        fs.verify().unwrap();
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::mount(dev, MountOptions::default()).unwrap();
+        assert!(fs.is_sealed());
+        assert!(fs.mount_options().read_only);
+        assert_eq!(fs.mkdir("/bar").unwrap_err().to_errno(), libc::EROFS);
+        fs.open("/foo", OpenMode::RO).unwrap();
+        fs.verify().unwrap();
+
+        let data_block = fs.bmap(0, 0).unwrap() as usize;
+        fs.dev.write_block(data_block, &[0xFF; BLOCK_SIZE]).unwrap();
+        match fs.verify().unwrap_err() {
+            SFSError::Corrupted(_) => (),
+            _ => panic!("Expected a corruption error."),
+        }
+    }
+
+    #[test]
+    fn remounting_switches_between_read_only_and_read_write() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.mkdir("/foo").unwrap();
+
+        fs.remount("remount,ro").unwrap();
+        assert_eq!(fs.io_stats().flushes, 1);
+        assert!(fs.mount_options().read_only);
+        assert_eq!(fs.mkdir("/bar").unwrap_err().to_errno(), libc::EROFS);
+
+        fs.remount("remount,rw,umask=077").unwrap();
+        assert_eq!(fs.mount_options().umask, 0o077);
+        fs.mkdir("/bar").unwrap();
+    }
+
+    #[test]
+    fn sealed_images_cant_be_remounted_read_write() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.seal().unwrap();
+
+        assert_eq!(fs.remount("rw").unwrap_err().to_errno(), libc::EROFS);
+        assert!(fs.mount_options().read_only);
+    }
+
+    #[test]
+    fn only_read_only_mounts_share_an_image() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let open = |options: SfsOptions| {
+            let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(64)
+                .clear_medium(false)
+                .build()
+                .unwrap();
+            options.open(dev)
+        };
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut writer = SFS::create(dev).unwrap();
+
+        assert!(matches!(open(SfsOptions::new()), Err(SFSError::Busy)));
+        assert!(matches!(
+            open(SfsOptions::new().read_only(true)),
+            Err(SFSError::Busy)
+        ));
+        open(SfsOptions::new().force(true)).unwrap();
+
+        writer.remount("ro").unwrap();
+        let reader = open(SfsOptions::new().read_only(true)).unwrap();
+        open(SfsOptions::new().read_only(true)).unwrap();
+        assert!(matches!(writer.remount("rw"), Err(SFSError::Busy)));
+        assert!(writer.mount_options().read_only);
+
+        drop(reader);
+        writer.remount("rw").unwrap();
+    }
+
+    #[test]
+    fn inodes_and_blocks_map_back_to_paths() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let foo = fs.mkdir("/foo").unwrap();
+        let bar = fs.mknod("/foo/bar", 0o20640, 0x0103).unwrap();
+
+        assert_eq!(fs.find_paths(bar).unwrap(), vec![PathBuf::from("/foo/bar")]);
+        assert_eq!(fs.find_paths(ROOT_INODE).unwrap(), vec![PathBuf::from("/")]);
+        assert!(fs.find_paths(bar + 1).unwrap().is_empty());
+
+        let stat = fs.istat(foo).unwrap();
+        assert_eq!(stat.mode, 0o40755);
+        assert_eq!(stat.blocks.len(), 1);
+        assert_eq!(fs.block_owner(stat.blocks[0]), Some(foo));
+        assert_eq!(
+            fs.read_inode(foo).unwrap(),
+            format!("{}:bar\n", bar).as_bytes()
+        );
+        assert_eq!(fs.istat(bar).unwrap().rdev, 0x0103);
+        assert!(matches!(fs.istat(bar + 1), Err(SFSError::DoesNotExist)));
+    }
+
+    #[test]
+    fn orphans_are_adopted_into_lost_and_found() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let a = fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+        let c = fs.open("/a/b/c", OpenMode::CREATE).unwrap();
+        fs.open("/d", OpenMode::CREATE).unwrap();
+        assert!(fs.adopt_orphans().unwrap().is_empty());
+
+        // Lose the entry for /a as if the root directory had been damaged.
+        let mut entries = fs.read_dir(ROOT_INODE).unwrap();
+        entries.remove(OsStr::new("a"));
+        write_dir(&mut fs, ROOT_INODE, entries);
+
+        assert_eq!(fs.adopt_orphans().unwrap(), vec![a]);
+        assert_eq!(
+            fs.open("/lost+found", OpenMode::RO).unwrap(),
+            LOST_FOUND_INODE
+        );
+        assert_eq!(fs.mode("/lost+found").unwrap(), 0o40700);
+        assert_eq!(
+            fs.find_paths(c).unwrap(),
+            vec![PathBuf::from(format!("/lost+found/#{}/b/c", a))]
+        );
+        fs.open("/d", OpenMode::RO).unwrap();
+        assert!(fs.adopt_orphans().unwrap().is_empty());
+    }
+
+    #[test]
+    fn orphans_on_legacy_images_leave_inode_one_alone() {
+        // Inode 1 is /foo here, from before the reserved inodes.
+        let dev = golden_device(include_str!("../testdata/v1-tree.hex"));
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        let foo_entries = fs.read_dir(1).unwrap();
+        let mut entries = fs.read_dir(ROOT_INODE).unwrap();
+        let bar = entries.remove(OsStr::new("bar.txt")).unwrap();
+        write_dir(&mut fs, ROOT_INODE, entries);
+
+        assert_eq!(fs.adopt_orphans().unwrap(), vec![bar]);
+        let lost_found = fs.open("/lost+found", OpenMode::RO).unwrap();
+        assert_ne!(lost_found, LOST_FOUND_INODE);
+        assert_eq!(fs.read_dir(1).unwrap(), foo_entries);
+        assert_eq!(
+            fs.find_paths(bar).unwrap(),
+            vec![PathBuf::from(format!("/lost+found/#{}", bar))]
+        );
+        assert_eq!(fs.find_paths(1).unwrap(), vec![PathBuf::from("/foo")]);
+    }
+
+    #[test]
+    fn verifying_unsealed_image_returns_error() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+
+        match fs.verify().unwrap_err() {
+            SFSError::InvalidArgument(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn metadata_writes_are_coalesced_until_commit() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.options.sync = SyncPolicy::Always;
+        for i in 0..5 {
+            fs.mkdir(format!("/d{}", i).as_str()).unwrap();
+        }
+        let unbuffered = fs.io_stats();
+        assert_eq!(unbuffered.flushes, 5);
+
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        for i in 0..5 {
+            fs.mkdir(format!("/d{}", i).as_str()).unwrap();
+        }
+        assert_eq!(fs.io_stats().flushes, 0);
+        fs.sync().unwrap();
+        let buffered = fs.io_stats();
+        assert_eq!(buffered.flushes, 1);
+        assert!(buffered.writes < unbuffered.writes);
+    }
+
+    #[test]
+    fn sync_policy_controls_when_metadata_is_flushed() {
+        let mut fs = SfsOptions::new()
+            .sync_policy(SyncPolicy::Never)
+            .create(create_test_device())
+            .unwrap();
+        fs.mkdir("/a").unwrap();
+        thread::sleep(MIN_FLUSH_WAIT);
+        fs.mkdir("/b").unwrap();
+        assert_eq!(fs.io_stats().flushes, 0);
+
+        // Remounting writes out what was held back.
+        fs.remount("commit=0").unwrap();
+        assert_eq!(fs.io_stats().flushes, 1);
+        fs.mkdir("/c").unwrap();
+        assert_eq!(fs.io_stats().flushes, 2);
+    }
+
+    #[test]
+    fn flusher_writes_out_changes_once_due() {
+        let fs = SfsOptions::new()
+            .sync_policy(SyncPolicy::Periodic(Duration::from_millis(20)))
+            .create(create_test_device())
+            .unwrap();
+        let fs = Arc::new(Mutex::new(fs));
+        let flusher = SFS::spawn_flusher(&fs);
+
+        fs.lock().unwrap().mkdir("/a").unwrap();
+        let start = Instant::now();
+        while fs.lock().unwrap().io_stats().flushes == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5), "never flushed");
+            thread::sleep(MIN_FLUSH_WAIT);
+        }
+
+        drop(fs);
+        flusher.join().unwrap();
+    }
+
+    #[test]
+    fn transactions_are_flushed_once_they_succeed() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.options.sync = SyncPolicy::Always;
+
+        fs.transaction(|fs| {
+            fs.mkdir("/data")?;
+            fs.open("/data/a", OpenMode::CREATE)?;
+            fs.open("/manifest", OpenMode::CREATE)
+        })
+        .unwrap();
+        assert_eq!(fs.io_stats().flushes, 1);
+        std::mem::forget(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        // The leaked instance still holds its lock on the image.
+        let mut fs = SfsOptions::new().force(true).open(dev).unwrap();
+        fs.open("/data/a", OpenMode::RO).unwrap();
+        fs.open("/manifest", OpenMode::RO).unwrap();
+    }
+
+    #[test]
+    fn failed_transactions_are_rolled_back() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let events = fs.watch("/", true).unwrap();
+        let before = fs.stats();
+
+        let err = fs
+            .transaction(|fs| {
+                fs.mkdir("/data")?;
+                fs.open("/data/a", OpenMode::CREATE)?;
+                fs.mkdir("/data/a").map(|_| ())
+            })
+            .unwrap_err();
+
+        assert_eq!(err.to_errno(), libc::EEXIST);
+        assert_eq!(fs.stats(), before);
+        assert_eq!(
+            fs.open("/data", OpenMode::RO).unwrap_err().to_errno(),
+            libc::ENOENT
+        );
+        assert!(events.try_recv().is_err());
+        assert_eq!(fs.mkdir("/data").unwrap(), FIRST_INODE);
+    }
+
+    #[test]
+    fn direct_writes_are_rejected_inside_transactions() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+
+        let err = fs.transaction(|fs| fs.sync()).unwrap_err();
+
+        assert_eq!(err.to_errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn errors_map_to_errno_values() {
+        let io_err = std::io::Error::from_raw_os_error(libc::EBADF);
+        let cases = vec![
+            (SFSError::InvalidArgument("".to_string()), libc::EINVAL),
+            (SFSError::DoesNotExist, libc::ENOENT),
+            (SFSError::InvalidBlock(io_err), libc::EBADF),
+            (
+                SFSError::InvalidBlock(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "block out of range",
+                )),
+                libc::EIO,
+            ),
+            (SFSError::ReadOnly, libc::EROFS),
+            (SFSError::NotADirectory, libc::ENOTDIR),
+            (SFSError::IsADirectory, libc::EISDIR),
+            (SFSError::NoSpace, libc::ENOSPC),
+            (SFSError::NameTooLong, libc::ENAMETOOLONG),
+            (SFSError::Exists, libc::EEXIST),
+            (SFSError::NotPermitted, libc::EPERM),
+            (SFSError::NotEmpty, libc::ENOTEMPTY),
+            (SFSError::BadMagic, libc::EINVAL),
+            (SFSError::UnsupportedVersion(3), libc::EINVAL),
+            (SFSError::BadChecksum, libc::EBADMSG),
+            (SFSError::Stale, libc::ESTALE),
+            (SFSError::Busy, libc::EBUSY),
+        ];
+
+        for (err, errno) in cases {
+            assert_eq!(err.to_errno(), errno, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn mkdir_on_existing_entry_returns_eexist() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+
+        assert_eq!(fs.mkdir("/foo").unwrap_err().to_errno(), libc::EEXIST);
+    }
+
+    #[test]
+    fn missing_subdirectory_returns_enoent() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert_eq!(
+            fs.open("/foo/bar", OpenMode::CREATE)
+                .unwrap_err()
+                .to_errno(),
+            libc::ENOENT
+        );
+    }
+
+    #[test]
+    fn traversing_through_a_file_returns_enotdir() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        assert_eq!(
+            fs.open("/foo/bar", OpenMode::RO).unwrap_err().to_errno(),
+            libc::ENOTDIR
+        );
+        assert_eq!(fs.mkdir("/foo/bar").unwrap_err().to_errno(), libc::ENOTDIR);
+    }
+
+    #[test]
+    fn running_out_of_inodes_returns_enospc() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        for i in FIRST_INODE..80 {
+            fs.open(format!("/{}", i), OpenMode::CREATE).unwrap();
+        }
+
+        assert_eq!(
+            fs.open("/80", OpenMode::CREATE).unwrap_err().to_errno(),
+            libc::ENOSPC
+        );
+    }
+
+    #[test]
+    fn opening_unformatted_device_returns_error() {
+        let dev = create_test_device();
+
+        match SFS::from_block_storage(dev) {
+            Err(SFSError::BadMagic) => (),
+            _ => panic!("Expected a bad magic error."),
+        }
+    }
+
+    #[test]
+    fn malformed_directory_entries_return_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        let block = fs.inodes.get(0).unwrap().blocks[0] as usize;
+
+        for garbage in [
+            &b"not-a-number:foo\n"[..],
+            &b"1-foo\n"[..],
+            &[0xFF, 0xFE, 0x00],
+        ] {
+            fs.dev.write_block(block, garbage).unwrap();
+
+            match fs.open("/foo", OpenMode::RO).unwrap_err() {
+                SFSError::Corrupted(_) => (),
+                _ => panic!("Expected a corruption error."),
+            }
+        }
+    }
+
+    #[test]
+    fn block_pointers_outside_data_region_return_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.inodes.get_mut(0).unwrap().blocks[0] = 2;
+
+        match fs.open("/foo", OpenMode::RO).unwrap_err() {
+            SFSError::Corrupted(_) => (),
+            _ => panic!("Expected a corruption error."),
+        }
+    }
+
+    #[test]
+    fn directory_size_tracks_entries() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let foo = fs.mkdir("/foo").unwrap();
+        let entry = format!("{}:foo\n", foo);
+
+        assert_eq!(fs.inodes.get(0).unwrap().size, entry.len() as u32);
+        assert_eq!(fs.read_file(0).unwrap(), entry.into_bytes());
+    }
+
+    #[test]
+    fn read_file_returns_partial_final_block() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let long_name = "f".repeat(100);
+        for i in 0..45 {
+            fs.open(format!("/{}{}", long_name, i), OpenMode::CREATE)
+                .unwrap();
+        }
+
+        let size = fs.inodes.get(0).unwrap().size as usize;
+        assert!(size > BLOCK_SIZE && size < 2 * BLOCK_SIZE);
+        assert_eq!(fs.read_file(0).unwrap().len(), size);
+        assert_eq!(fs.read_dir(0).unwrap().len(), 45);
+    }
+
+    #[test]
+    fn size_larger_than_allocated_blocks_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.inodes.get_mut(0).unwrap().size = BLOCK_SIZE as u32 + 1;
+
+        match fs.read_file(0).unwrap_err() {
+            SFSError::Corrupted(_) => (),
+            _ => panic!("Expected a corruption error."),
+        }
+    }
+
+    #[test]
+    fn shrinking_directory_zero_pads_final_block() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let block = fs.mkdir("/foo").unwrap();
+        let root_block = fs.inodes.get(0).unwrap().blocks[0] as usize;
+        let mut entries = fs.read_dir(0).unwrap();
+        entries.insert(OsString::from("bar"), block);
+        write_dir(&mut fs, 0, entries);
+
+        let mut entries = fs.read_dir(0).unwrap();
+        entries.remove(&OsString::from("bar"));
+        write_dir(&mut fs, 0, entries);
+
+        let mut buf = vec![0xFF; BLOCK_SIZE];
+        fs.dev.read_block(root_block, &mut buf).unwrap();
+        let size = fs.inodes.get(0).unwrap().size as usize;
+        assert!(buf[size..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn names_up_to_the_limit_are_accepted() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let name = format!("/{}", "a".repeat(MAX_NAME_LEN as usize));
+
+        let inum = fs.open(&name, OpenMode::CREATE).unwrap();
+
+        assert_eq!(fs.open(&name, OpenMode::RO).unwrap(), inum);
+    }
+
+    #[test]
+    fn names_over_the_limit_return_enametoolong() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let name = format!("/{}", "a".repeat(MAX_NAME_LEN as usize + 1));
+
+        for err in [
+            fs.open(&name, OpenMode::RO).unwrap_err(),
+            fs.open(&name, OpenMode::CREATE).unwrap_err(),
+            fs.mkdir(&name).unwrap_err(),
+        ] {
+            assert_eq!(err.to_errno(), libc::ENAMETOOLONG);
+        }
+    }
+
+    #[test]
+    fn paths_over_the_depth_limit_return_enametoolong() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let path = "/a".repeat(MAX_PATH_DEPTH + 1);
+
+        assert_eq!(
+            fs.open(&path, OpenMode::RO).unwrap_err().to_errno(),
+            libc::ENAMETOOLONG
+        );
+    }
+
+    #[test]
+    fn resolving_through_missing_or_non_directories_fails() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.open("/foo", OpenMode::CREATE).unwrap();
+
+        for err in [
+            fs.open("/foo/bar", OpenMode::RO).unwrap_err(),
+            fs.open("/foo/bar", OpenMode::CREATE).unwrap_err(),
+            fs.mkdir("/foo/bar").unwrap_err(),
+        ] {
+            assert_eq!(err.to_errno(), libc::ENOTDIR);
+        }
+        for err in [
+            fs.open("/missing/bar", OpenMode::CREATE).unwrap_err(),
+            fs.mkdir("/missing/bar").unwrap_err(),
+            fs.mknod("/missing/bar", 0o10644, 0).unwrap_err(),
+        ] {
+            assert_eq!(err.to_errno(), libc::ENOENT);
+        }
+        assert_eq!(fs.mkdir("/").unwrap_err().to_errno(), libc::EEXIST);
+    }
+
+    #[test]
+    fn names_directories_cant_store_are_rejected() {
+        use std::os::unix::ffi::OsStrExt;
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let not_utf8 = Path::new(OsStr::from_bytes(b"/foo\xff"));
+
+        for err in [
+            fs.open("/foo\nbar", OpenMode::CREATE).unwrap_err(),
+            fs.mkdir("/foo\nbar").unwrap_err(),
+            fs.mkdir(not_utf8).unwrap_err(),
+            fs.mknod(not_utf8, 0o10644, 0).unwrap_err(),
+        ] {
+            assert_eq!(err.to_errno(), libc::EINVAL);
+        }
+        assert!(fs.read_dir(ROOT_INODE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn name_limit_is_recorded_in_superblock() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        SFS::create(dev).unwrap();
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let fs = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(fs.super_block.max_name_len, MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn read_only_mount_rejects_modifications() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        SFS::create(dev).unwrap().mkdir("/foo").unwrap();
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::mount(dev, "ro".parse().unwrap()).unwrap();
+
+        assert!(fs.mount_options().read_only);
+        match fs.mkdir("/bar").unwrap_err() {
+            SFSError::ReadOnly => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.open("/bar", OpenMode::CREATE).unwrap_err() {
+            SFSError::ReadOnly => (),
+            _ => panic!("Unexpected error type."),
+        }
+        assert!(fs.resize(56).is_err());
+    }
+
+    #[test]
+    fn devices_of_unknown_size_mount_but_cant_grow() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        SFS::create(dev).unwrap();
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let dev = CrashingDevice {
+            dev,
+            writes_left: usize::MAX,
+            discarded: vec![],
+        };
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+
+        assert!(matches!(fs.resize(57), Err(SFSError::InvalidArgument(_))));
+        fs.resize(20).unwrap();
+    }
+
+    #[test]
+    fn can_grow_data_region_into_free_device_space() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(128)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+
+        fs.resize(120).unwrap();
+        assert_eq!(fs.stats().total_blocks, 120);
+        drop(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(128)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let fs = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(fs.stats().total_blocks, 120);
+    }
+
+    #[test]
+    fn resize_beyond_device_returns_error() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        assert!(fs.resize(57).is_err());
+        assert_eq!(fs.stats().total_blocks, 56);
+    }
+
+    #[test]
+    fn shrink_fails_when_cut_off_blocks_are_in_use() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.mkdir("/foo/bar").unwrap();
+
+        assert!(fs.resize(1).is_err());
+        fs.resize(2).unwrap();
+        assert_eq!(fs.stats().free_blocks, 0);
+    }
+
+    /// Creates /a and /b, growing /a past a single block so its second block lands after
+    /// /b's, and returns /a's inumber.
+    fn fragment_directories<T: BlockStorage>(fs: &mut SFS<T>) -> u32 {
+        let a = fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/x").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.mkdir("/b/y").unwrap();
+        let long_name = "f".repeat(100);
+        for i in 0..45 {
+            fs.open(format!("/a/{}{}", long_name, i), OpenMode::CREATE)
+                .unwrap();
+        }
+        a
+    }
+
+    /// Rewrites the entire contents of directory `dir`, for planting damaged or unusual
+    /// directories. The file system itself only ever appends entries.
+    fn write_dir<T: BlockStorage>(fs: &mut SFS<T>, dir: u32, entries: HashMap<OsString, u32>) {
+        fs.dcache.invalidate_dir(dir);
+        let contents: String = entries
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", v, k.to_str().unwrap()))
+            .collect();
+
+        let mut blocks = fs.data_blocks(dir).unwrap();
+        fs.allocate_blocks(&mut blocks, 1 + (contents.len() / BLOCK_SIZE))
+            .unwrap();
+        let node = fs.inode_mut(dir);
+        node.blocks[0..blocks.len()].copy_from_slice(&blocks);
+        node.size = contents.len() as u32;
+        fs.write_blocks(&blocks, contents.as_bytes()).unwrap();
+    }
+
+    /// Stops writing blocks once `writes_left` runs out, as if the power was cut, and
+    /// records the blocks discarded. It doesn't report its size.
+    struct CrashingDevice {
+        dev: FileBlockEmulator,
+        writes_left: usize,
+        discarded: Vec<usize>,
+    }
+
+    impl BlockStorage for CrashingDevice {
+        fn open_disk<P: AsRef<Path>>(_: P, _: usize) -> std::io::Result<Self> {
+            unimplemented!()
+        }
+
+        fn read_block(&mut self, blocknr: usize, buf: &mut [u8]) -> std::io::Result<()> {
+            self.dev.read_block(blocknr, buf)
+        }
+
+        fn write_block(&mut self, blocknr: usize, buf: &[u8]) -> std::io::Result<()> {
+            if self.writes_left == 0 {
+                return Err(std::io::Error::other("crashed"));
+            }
+            self.writes_left -= 1;
+            self.dev.write_block(blocknr, buf)
+        }
+
+        fn sync_disk(&mut self) -> std::io::Result<()> {
+            self.dev.sync_disk()
+        }
+
+        fn discard(&mut self, blocknr: usize) -> std::io::Result<()> {
+            self.discarded.push(blocknr);
+            self.dev.discard(blocknr)
+        }
+    }
+
+    #[test]
+    fn defrag_makes_directory_blocks_contiguous() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let a = fragment_directories(&mut fs);
+        assert_eq!(fs.inodes.get(a).unwrap().blocks[0..2], [9, 11]);
+
+        let report = fs.defrag().unwrap();
+
+        assert_eq!(fs.inodes.get(a).unwrap().blocks[0..2], [9, 10]);
+        // /b's block goes out of the way and back again.
+        assert_eq!(report.blocks_moved, 3);
+        assert_eq!(report.after.used_blocks, report.before.used_blocks);
+        assert_eq!(fs.read_dir(a).unwrap().len(), 46);
+        assert!(fs.open("/b/y", OpenMode::RO).is_ok());
+    }
+
+    #[test]
+    fn defrag_interrupted_at_any_write_leaves_files_intact() {
+        for writes in 0.. {
+            let disk = tempfile::NamedTempFile::new().unwrap();
+            let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(64)
+                .build()
+                .unwrap();
+            let dev = CrashingDevice {
+                dev,
+                writes_left: usize::MAX,
+                discarded: vec![],
+            };
+            let mut fs = SFS::create(dev).unwrap();
+            let a = fragment_directories(&mut fs);
+            fs.sync().unwrap();
+            fs.dev.writes_left = writes;
+            let finished = fs.defrag().is_ok();
+            drop(fs);
+
+            let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+                .with_block_size(64)
+                .clear_medium(false)
+                .build()
+                .unwrap();
+            let mut fs = SFS::from_block_storage(dev).unwrap();
+            assert_eq!(fs.read_dir(a).unwrap().len(), 46, "after {} writes", writes);
+            assert!(
+                fs.open("/b/y", OpenMode::RO).is_ok(),
+                "after {} writes",
+                writes
+            );
+            if finished {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn bmap_maps_logical_blocks_to_disk_blocks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let a = fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/x").unwrap();
+        fs.mkdir("/b").unwrap();
+        fs.mkdir("/b/y").unwrap();
+        // Grow /a past a single block so its second block lands after /b's.
+        let long_name = "f".repeat(100);
+        for i in 0..45 {
+            fs.open(format!("/a/{}{}", long_name, i), OpenMode::CREATE)
+                .unwrap();
+        }
+
+        assert_eq!(fs.bmap(a, 0).unwrap(), 9);
+        assert_eq!(fs.bmap(a, 1).unwrap(), 11);
+        assert_eq!(fs.bmap(a, 2).unwrap(), 0);
+        assert_eq!(fs.bmap(a, 100).unwrap(), 0);
+        match fs.bmap(70, 0).unwrap_err() {
+            SFSError::DoesNotExist => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn fresh_filesystem_reports_all_space_free() {
+        let dev = create_test_device();
+        let fs = SFS::create(dev).unwrap();
+
+        let stats = fs.stats();
+        assert_eq!(stats.total_blocks, 56);
+        assert_eq!(stats.free_blocks, 56);
+        assert_eq!(stats.largest_free_extent, 56);
+        assert_eq!(stats.used_inodes, 1);
+        assert_eq!(stats.free_inodes, 80 - FIRST_INODE as usize);
+        assert_eq!(stats.fragmentation, 0.0);
+    }
+
+    #[test]
+    fn reads_update_access_time_per_atime_policy() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        let hour = Duration::from_secs(60 * 60);
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let a = fs.mkdir("/a").unwrap();
+        assert_eq!(fs.stats().atime, AtimePolicy::RelAtime);
+
+        // Never accessed since the last modification.
+        fs.read_inode(a).unwrap();
+        let accessed = fs.inodes.get(a).unwrap().atime();
+        assert!(accessed > epoch);
+        // Recently accessed, relatime leaves it alone.
+        let recent = SystemTime::now() - hour;
+        fs.inodes.get_mut(a).unwrap().set_mtime(recent - hour);
+        fs.inodes.get_mut(a).unwrap().set_atime(recent);
+        fs.read_inode(a).unwrap();
+        assert_eq!(fs.inodes.get(a).unwrap().atime(), whole_seconds(recent));
+
+        fs.remount("strictatime").unwrap();
+        fs.read_inode(a).unwrap();
+        assert!(fs.inodes.get(a).unwrap().atime() > whole_seconds(recent));
+
+        fs.remount("noatime").unwrap();
+        assert_eq!(fs.stats().atime, AtimePolicy::NoAtime);
+        fs.inodes.get_mut(a).unwrap().set_atime(epoch);
+        fs.read_inode(a).unwrap();
+        assert_eq!(fs.inodes.get(a).unwrap().atime(), epoch);
+
+        fs.remount("ro,strictatime").unwrap();
+        fs.read_inode(a).unwrap();
+        assert_eq!(fs.inodes.get(a).unwrap().atime(), epoch);
+    }
+
+    #[test]
+    fn adding_entries_makes_relatime_refresh_access_time() {
+        let hour = Duration::from_secs(60 * 60);
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        let a = fs.mkdir("/a").unwrap();
+        assert!(fs.inodes.get(a).unwrap().mtime() > SystemTime::UNIX_EPOCH);
+
+        // Read an hour ago, after the last modification.
+        let read = SystemTime::now() - hour;
+        fs.inodes.get_mut(a).unwrap().set_mtime(read - hour);
+        fs.inodes.get_mut(a).unwrap().set_atime(read);
+        fs.read_inode(a).unwrap();
+        assert_eq!(fs.inodes.get(a).unwrap().atime(), whole_seconds(read));
+
+        fs.mkdir("/a/b").unwrap();
+        fs.read_inode(a).unwrap();
+        assert!(fs.inodes.get(a).unwrap().atime() > whole_seconds(read));
+    }
+
+    /// Truncates `time` to the whole seconds an inode stores.
+    fn whole_seconds(time: SystemTime) -> SystemTime {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn stats_count_blocks_used_by_directories() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        fs.mkdir("/foo").unwrap();
+        fs.mkdir("/foo/bar").unwrap();
+
+        let stats = fs.stats();
+        // The root and /foo each hold a block of entries, /foo/bar is still empty.
+        assert_eq!(stats.used_blocks, 2);
+        assert_eq!(stats.free_blocks, 54);
+        assert_eq!(stats.used_inodes, 3);
+    }
+
+    #[test]
+    fn dir_usage_includes_subdirectories() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+
+        fs.mkdir("/foo").unwrap();
+        fs.mkdir("/foo/bar").unwrap();
+        fs.open("/foo/bar/baz.txt", OpenMode::CREATE).unwrap();
+
+        let usage = fs.dir_usage("/").unwrap();
+        assert_eq!(
+            usage,
+            vec![
+                DirUsage {
+                    path: PathBuf::from("/foo/bar"),
+                    blocks: 1
+                },
+                DirUsage {
+                    path: PathBuf::from("/foo"),
+                    blocks: 2
+                },
+                DirUsage {
+                    path: PathBuf::from("/"),
+                    blocks: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mounting_image_larger_than_device_returns_error() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        SFS::create(dev).unwrap();
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(32)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        match SFS::from_block_storage(dev) {
+            Err(SFSError::Corrupted(_)) => (),
+            _ => panic!("Unexpected result."),
+        }
+    }
+
+    #[test]
+    fn block_map_shows_owners_of_data_blocks() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.mkdir("/foo/bar").unwrap();
+        fs.data_map.set_reserved(5);
+
+        assert_eq!(
+            fs.block_map(),
+            format!("    0: SBBIIIII05...!{}\n", ".".repeat(50))
+        );
+    }
+
+    #[test]
+    fn tree_dot_lists_every_entry() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.mkdir("/foo").unwrap();
+        fs.open("/foo/say \"hi\"", OpenMode::CREATE).unwrap();
+        fs.open("/bar", OpenMode::CREATE).unwrap();
+
+        assert_eq!(
+            fs.tree_dot().unwrap(),
+            concat!(
+                "digraph sfs {\n",
+                "  n0 [label=\"0\" shape=folder];\n",
+                "  n0 -> n7 [label=\"bar\"];\n",
+                "  n7 [label=\"7\" shape=note];\n",
+                "  n0 -> n5 [label=\"foo\"];\n",
+                "  n5 [label=\"5\" shape=folder];\n",
+                "  n5 -> n6 [label=\"say \\\"hi\\\"\"];\n",
+                "  n6 [label=\"6\" shape=note];\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn dir_usage_detects_directory_cycles() {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let foo = fs.mkdir("/foo").unwrap();
+
+        let mut entries = HashMap::new();
+        entries.insert(OsString::from("loop"), 0);
+        write_dir(&mut fs, foo, entries);
+
+        match fs.dir_usage("/").unwrap_err() {
+            SFSError::Corrupted(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    /// An operation the differential test applies to both SFS and the host file system.
+    #[derive(Debug)]
+    enum Op {
+        Mkdir(PathBuf),
+        Create(PathBuf),
+        Lookup(PathBuf),
+    }
+
+    fn random_op(rng: &mut StdRng) -> Op {
+        let mut path = PathBuf::from("/");
+        for _ in 0..rng.gen_range(1, 4) {
+            path.push(["a", "b", "c"][rng.gen_range(0, 3)]);
+        }
+        match rng.gen_range(0, 3) {
+            0 => Op::Mkdir(path),
+            1 => Op::Create(path),
+            _ => Op::Lookup(path),
+        }
+    }
+
+    fn host_path(root: &Path, path: &Path) -> PathBuf {
+        root.join(path.strip_prefix("/").unwrap())
+    }
+
+    /// Applies `op` to both file systems and returns the errno each one failed with, if any.
+    fn apply_op(
+        fs: &mut SFS<FileBlockEmulator>,
+        root: &Path,
+        op: &Op,
+    ) -> (Option<libc::c_int>, Option<libc::c_int>) {
+        let (sfs, host) = match op {
+            Op::Mkdir(path) => (
+                fs.mkdir(path.to_str().unwrap()).map(|_| ()),
+                std::fs::create_dir(host_path(root, path)),
+            ),
+            Op::Create(path) => (
+                fs.open(path, OpenMode::CREATE).map(|_| ()),
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(host_path(root, path))
+                    .map(|_| ()),
+            ),
+            Op::Lookup(path) => (
+                fs.open(path, OpenMode::RO).map(|_| ()),
+                std::fs::symlink_metadata(host_path(root, path)).map(|_| ()),
+            ),
+        };
+        (
+            sfs.err().map(|err| err.to_errno()),
+            host.err().map(|err| err.raw_os_error().unwrap()),
+        )
+    }
+
+    fn sfs_tree(
+        fs: &mut SFS<FileBlockEmulator>,
+        path: PathBuf,
+        inum: u32,
+        tree: &mut Vec<(PathBuf, bool)>,
+    ) {
+        for (name, child) in fs.read_dir(inum).unwrap() {
+            let child_path = path.join(name);
+            let is_dir = fs.inodes.get(child).unwrap().is_dir();
+            tree.push((child_path.clone(), is_dir));
+            if is_dir {
+                sfs_tree(fs, child_path, child, tree);
+            }
+        }
+    }
+
+    fn host_tree(root: &Path, path: PathBuf, tree: &mut Vec<(PathBuf, bool)>) {
+        for entry in std::fs::read_dir(host_path(root, &path)).unwrap() {
+            let entry = entry.unwrap();
+            let child_path = path.join(entry.file_name());
+            let is_dir = entry.file_type().unwrap().is_dir();
+            tree.push((child_path.clone(), is_dir));
+            if is_dir {
+                host_tree(root, child_path, tree);
+            }
+        }
+    }
+
+    #[test]
+    fn random_operations_match_host_file_system() {
+        for seed in 0..100 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut fs = SFS::create(create_test_device()).unwrap();
+            let host = tempfile::tempdir().unwrap();
+
+            let mut ops = vec![];
+            for _ in 0..30 {
+                let op = random_op(&mut rng);
+                let (sfs_err, host_err) = apply_op(&mut fs, host.path(), &op);
+                ops.push(op);
+                assert_eq!(sfs_err, host_err, "seed {} diverged: {:?}", seed, ops);
+            }
+
+            let mut sfs_entries = vec![];
+            sfs_tree(&mut fs, PathBuf::from("/"), 0, &mut sfs_entries);
+            sfs_entries.sort();
+            let mut host_entries = vec![];
+            host_tree(host.path(), PathBuf::from("/"), &mut host_entries);
+            host_entries.sort();
+            assert_eq!(
+                sfs_entries, host_entries,
+                "seed {} diverged: {:?}",
+                seed, ops
+            );
+        }
+    }
+
+    #[test]
+    fn create_writes_golden_image() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+
+        let mut image = vec![0; 64 * BLOCK_SIZE];
+        for (i, block) in image.chunks_mut(BLOCK_SIZE).enumerate() {
+            fs.dev.read_block(i, block).unwrap();
+        }
+        assert!(
+            image == golden_image(include_str!("../testdata/v4-empty.hex")),
+            "SFS::create no longer writes the v4 format, add a new golden image if this is intended"
+        );
+    }
+
+    #[test]
+    fn torn_superblock_write_falls_back_to_other_copy() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        let mut fs = SFS::create(dev).unwrap();
+        fs.set_error_policy(ErrorPolicy::RemountReadOnly).unwrap();
+        fs.set_error_policy(ErrorPolicy::Panic).unwrap();
+
+        // The last write went to the second copy, damage it as if the write had been torn.
+        let mut block = vec![0; BLOCK_SIZE];
+        fs.dev.read_block(SUPERBLOCK_INDEX, &mut block).unwrap();
+        block[crate::sb::SECTOR_SIZE + 8] ^= 1;
+        fs.dev.write_block(SUPERBLOCK_INDEX, &block).unwrap();
+        std::mem::forget(fs);
+
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        // The leaked instance still holds its lock on the image.
+        let fs = SfsOptions::new().force(true).open(dev).unwrap();
+        assert_eq!(fs.error_policy(), ErrorPolicy::RemountReadOnly);
+    }
+
+    #[test]
+    fn read_only_storage_mounts_read_only() {
         let disk = tempfile::NamedTempFile::new().unwrap();
         let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
             .with_block_size(64)
             .build()
             .unwrap();
-        // Initialize the filesystem.
         SFS::create(dev).unwrap();
 
+        let dev = FileBlockEmulatorBuilder::from(std::fs::File::open(disk.path()).unwrap())
+            .with_block_size(64)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        let mut fs = SFS::mount(dev, MountOptions::default()).unwrap();
+
+        assert!(fs.mount_options().read_only);
+        assert!(fs.stats().read_only);
+        assert_eq!(fs.mkdir("/foo").unwrap_err().to_errno(), libc::EROFS);
+    }
+
+    #[test]
+    fn can_mount_golden_images() {
+        // Images from before the inode reservation hand out every inode after the root.
+        let images = [
+            (include_str!("../testdata/v0-empty.hex"), ROOT_INODE + 1),
+            (include_str!("../testdata/v1-empty.hex"), ROOT_INODE + 1),
+            (include_str!("../testdata/v2-empty.hex"), ROOT_INODE + 1),
+            (include_str!("../testdata/v3-empty.hex"), ROOT_INODE + 1),
+            (include_str!("../testdata/v4-empty.hex"), FIRST_INODE),
+        ];
+        for (dump, first_inode) in images.iter() {
+            let mut fs = SFS::from_block_storage(golden_device(dump)).unwrap();
+
+            assert_eq!(fs.super_block.max_name_len, MAX_NAME_LEN);
+            assert_eq!(fs.open("/", OpenMode::RO).unwrap(), 0);
+            assert!(fs.read_dir(0).unwrap().is_empty());
+            assert_eq!(fs.mkdir("/foo").unwrap(), *first_inode);
+        }
+    }
+
+    #[test]
+    fn can_read_golden_tree_image() {
+        let dev = golden_device(include_str!("../testdata/v1-tree.hex"));
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+
+        let foo = fs.open("/foo", OpenMode::RO).unwrap();
+        let baz = fs.open("/foo/baz", OpenMode::RO).unwrap();
+        let bar = fs.open("/bar.txt", OpenMode::RO).unwrap();
+        assert_eq!((foo, bar, baz), (1, 2, 3));
+        assert!(fs.inodes.get(baz).unwrap().is_dir());
+        assert_eq!(
+            fs.mode("/bar.txt").unwrap() & FILE_TYPE_MASK,
+            libc::S_IFREG as u16
+        );
+        assert_eq!(
+            fs.dir_usage("/").unwrap(),
+            vec![
+                DirUsage {
+                    path: PathBuf::from("/foo/baz"),
+                    blocks: 0
+                },
+                DirUsage {
+                    path: PathBuf::from("/foo"),
+                    blocks: 1
+                },
+                DirUsage {
+                    path: PathBuf::from("/"),
+                    blocks: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn populated_baseline_images_mount_empty() {
+        let dev = golden_device(include_str!("../testdata/v0-tree.hex"));
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+
+        assert!(fs.read_dir(ROOT_INODE).unwrap().is_empty());
+        assert_eq!(
+            fs.open("/foo", OpenMode::RO).unwrap_err().to_errno(),
+            libc::ENOENT
+        );
+        // The stray listing's block is free, so recreating the tree overwrites it.
+        assert_eq!(fs.mkdir("/foo").unwrap(), ROOT_INODE + 1);
+        fs.open("/foo/bar.txt", OpenMode::CREATE).unwrap();
+        assert_eq!(fs.bmap(ROOT_INODE, 0).unwrap(), DATA_START as u32);
+        assert_eq!(
+            fs.mode("/foo").unwrap() & FILE_TYPE_MASK,
+            libc::S_IFDIR as u16
+        );
+        assert_eq!(
+            fs.mode("/foo/bar.txt").unwrap() & FILE_TYPE_MASK,
+            libc::S_IFREG as u16
+        );
+    }
+
+    #[test]
+    fn unversioned_images_reject_character_devices() {
+        let mut image = golden_image(include_str!("../testdata/v1-tree.hex"));
+        // Give /bar.txt the type the original format used for every inode but the root.
+        image[INODE_START * BLOCK_SIZE + 2 * NODE_SIZE + 1] = 0x20;
+        let mut dev = create_test_device();
+        for (i, block) in image.chunks(BLOCK_SIZE).enumerate() {
+            dev.write_block(i, block).unwrap();
+        }
+
+        assert!(matches!(
+            SFS::from_block_storage(dev),
+            Err(SFSError::Corrupted(_))
+        ));
+
+        let dev = golden_device(include_str!("../testdata/v0-empty.hex"));
+        let mut fs = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(
+            fs.mknod("/null", libc::S_IFCHR as u16 | 0o666, 0x0103)
+                .unwrap_err()
+                .to_errno(),
+            libc::EINVAL
+        );
+        fs.mknod("/pipe", libc::S_IFIFO as u16 | 0o644, 0).unwrap();
+    }
+
+    #[test]
+    fn secure_delete_zeroes_blocks_vacated_by_defrag() {
+        let dev = CrashingDevice {
+            dev: create_test_device(),
+            writes_left: usize::MAX,
+            discarded: vec![],
+        };
+        let mut fs = SFS::create(dev).unwrap();
+        fs.options.secure_delete = true;
+        let a = fs.mkdir("/a").unwrap();
+        fs.open("/a/secret.txt", OpenMode::CREATE).unwrap();
+
+        // Move /a's block to the far end of the data region, leaving a hole behind it.
+        let mut block = vec![0; BLOCK_SIZE];
+        fs.dev.read_block(9, &mut block).unwrap();
+        fs.dev.write_block(20, &block).unwrap();
+        fs.data_map.set_free(9 - DATA_START);
+        fs.data_map.set_reserved(20 - DATA_START);
+        fs.inodes.get_mut(a).unwrap().blocks[0] = 20;
+
+        fs.defrag().unwrap();
+
+        assert_eq!(fs.inodes.get(a).unwrap().blocks[0], 9);
+        fs.dev.read_block(20, &mut block).unwrap();
+        assert!(block.iter().all(|&b| b == 0));
+        assert_eq!(fs.dev.discarded, vec![20]);
+        assert!(fs.open("/a/secret.txt", OpenMode::RO).is_ok());
+    }
+
+    /// Creates /a and points its first block outside of the data region.
+    fn corrupt_test_filesystem() -> SFS<FileBlockEmulator> {
+        let dev = create_test_device();
+        let mut fs = SFS::create(dev).unwrap();
+        let a = fs.mkdir("/a").unwrap();
+        fs.inodes.get_mut(a).unwrap().blocks[0] = 1000;
+        fs
+    }
+
+    #[test]
+    fn corruption_latches_read_only_under_remount_policy() {
+        let mut fs = corrupt_test_filesystem();
+        fs.set_error_policy(ErrorPolicy::RemountReadOnly).unwrap();
+
+        match fs.open("/a/b", OpenMode::RO).unwrap_err() {
+            SFSError::Corrupted(_) => (),
+            _ => panic!("Unexpected error type."),
+        }
+        match fs.mkdir("/b").unwrap_err() {
+            SFSError::ReadOnly => (),
+            _ => panic!("Unexpected error type."),
+        }
+    }
+
+    #[test]
+    fn corruption_is_only_reported_under_continue_policy() {
+        let mut fs = corrupt_test_filesystem();
+
+        assert_eq!(fs.error_policy(), ErrorPolicy::Continue);
+        assert!(fs.open("/a/b", OpenMode::RO).is_err());
+        assert!(fs.mkdir("/b").is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn corruption_panics_under_panic_policy() {
+        let mut fs = corrupt_test_filesystem();
+        fs.set_error_policy(ErrorPolicy::Panic).unwrap();
+
+        let _ = fs.open("/a/b", OpenMode::RO);
+    }
+
+    #[test]
+    fn mount_option_overrides_recorded_error_policy() {
+        let mut fs = SFS::create(create_test_device()).unwrap();
+        fs.set_error_policy(ErrorPolicy::Panic).unwrap();
+
+        fs.remount("errors=continue").unwrap();
+        assert_eq!(fs.error_policy(), ErrorPolicy::Continue);
+        fs.report(SFSError::Corrupted("test".to_string()));
+        assert_eq!(fs.super_block.errors, ErrorPolicy::Panic.to_raw());
+    }
+
+    #[test]
+    fn error_policy_persists_across_mounts() {
+        let disk = tempfile::NamedTempFile::new().unwrap();
+        let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap();
+        SFS::create(dev)
+            .unwrap()
+            .set_error_policy(ErrorPolicy::RemountReadOnly)
+            .unwrap();
+
         let dev = FileBlockEmulatorBuilder::from(disk.reopen().unwrap())
             .with_block_size(64)
-            // Don't reset initialized disk.
             .clear_medium(false)
             .build()
             .unwrap();
-        let fs: SFS<FileBlockEmulator> = SFS::from_block_storage(dev).unwrap();
-        assert_eq!(fs.inodes.total_nodes(), 1);
+        let fs = SFS::from_block_storage(dev).unwrap();
+        assert_eq!(fs.error_policy(), ErrorPolicy::RemountReadOnly);
     }
 }
@@ -0,0 +1,130 @@
+use crate::alloc::{Bitmap, BITMAP_CAPACITY};
+use crate::fs::{parse_dir, BLOCK_SIZE, NODE_SIZE, SB_MAGIC};
+use crate::io::{BlockStorage, FileBlockEmulator, FileBlockEmulatorBuilder};
+use crate::node::{InodeGroup, FIRST_INODE};
+use crate::options::{ErrorPolicy, SfsOptions};
+use crate::sb::SuperBlock;
+
+/// The number of blocks in the device fuzzed images are mounted from.
+const IMAGE_BLOCKS: usize = 64;
+
+/// Parses `data` as the block holding the superblock.
+pub fn superblock(data: &[u8]) {
+    let _ = SuperBlock::parse_block(data, SB_MAGIC);
+}
+
+/// Parses `data` as an allocation bitmap and scans every entry.
+pub fn bitmap(data: &[u8]) {
+    if let Ok(map) = Bitmap::parse(data) {
+        map.free_extents(BITMAP_CAPACITY);
+    }
+}
+
+/// Decodes `data` as a block of inodes, treating every inode in the block as allocated.
+pub fn inode_block(data: &[u8]) {
+    let mut allocations = Bitmap::new();
+    for i in 0..BLOCK_SIZE / NODE_SIZE {
+        allocations.set_reserved(i);
+    }
+    let _ = InodeGroup::open(allocations, FIRST_INODE).load_block(0, data);
+}
+
+/// Parses `data` as the contents of a directory.
+pub fn dir(data: &[u8]) {
+    let _ = parse_dir(data);
+}
+
+/// Mounts `data` as a disk image, walks the whole directory tree, and attempts to create a
+/// directory. The image is truncated or zero padded to 64 blocks. The error policy is forced
+/// to `Continue`, an image asking to panic on corruption would otherwise turn every
+/// corruption found into a crash.
+pub fn image(data: &[u8]) {
+    let options = SfsOptions::new().error_policy(ErrorPolicy::Continue);
+    if let Ok(mut fs) = options.open(image_device(data)) {
+        fs.stats();
+        let _ = fs.dir_usage("/");
+        let _ = fs.mkdir("/fuzz");
+    }
+}
+
+/// Writes `data` to the start of a fresh 64 block device.
+fn image_device(data: &[u8]) -> FileBlockEmulator {
+    let file = tempfile::tempfile().expect("could not create backing file");
+    let mut dev = FileBlockEmulatorBuilder::from(file)
+        .with_block_size(IMAGE_BLOCKS)
+        .build()
+        .expect("could not initialize disk emulator");
+    for (i, chunk) in data.chunks(BLOCK_SIZE).take(IMAGE_BLOCKS).enumerate() {
+        dev.write_block(i, chunk)
+            .expect("could not write image block");
+    }
+    dev
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::SFS;
+    use std::fs;
+    use std::path::Path;
+
+    type Target = fn(&[u8]);
+
+    fn seeds(target: &str) -> Vec<Vec<u8>> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fuzz/corpus")
+            .join(target);
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn seed_corpus_runs_cleanly() {
+        let targets: [(&str, Target); 5] = [
+            ("superblock", superblock),
+            ("bitmap", bitmap),
+            ("inode_block", inode_block),
+            ("dir", dir),
+            ("image", image),
+        ];
+        for (target, run) in targets.iter() {
+            for seed in seeds(target) {
+                run(&seed);
+                run(&seed[..seed.len() / 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn seed_image_mounts() {
+        let seed = &seeds("image")[0];
+
+        let mut fs = SFS::from_block_storage(image_device(seed)).unwrap();
+        assert_eq!(fs.dir_usage("/").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn images_asking_to_panic_on_corruption_dont_crash() {
+        // A version 1 superblock, which has no checksum, with errors=2 (panic).
+        let mut data = vec![0; 9 * BLOCK_SIZE];
+        for (offset, value) in [(0, SB_MAGIC), (4, 80), (8, 56), (28, 255), (32, 2), (36, 1)] {
+            data[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        }
+        // Data block 8 and the root inode are allocated.
+        data[BLOCK_SIZE] = 1;
+        data[2 * BLOCK_SIZE] = 1;
+        // The root directory points at block 8, holding an entry without a separator.
+        let root = 3 * BLOCK_SIZE;
+        data[root..root + 2].copy_from_slice(&0x4000u16.to_le_bytes());
+        data[root + 8..root + 12].copy_from_slice(&8u32.to_le_bytes());
+        data[root + 196..root + 200].copy_from_slice(&8u32.to_le_bytes());
+        data[8 * BLOCK_SIZE..8 * BLOCK_SIZE + 8].copy_from_slice(b"garbage\n");
+
+        let fs = SFS::from_block_storage(image_device(&data)).unwrap();
+        assert_eq!(fs.error_policy(), ErrorPolicy::Panic);
+        drop(fs);
+        image(&data);
+    }
+}
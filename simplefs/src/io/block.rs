@@ -21,16 +21,100 @@ pub trait BlockStorage {
     ///
     /// Attempting to read a block out of range will return an error.
     fn read_block(&mut self, blocknr: BlockNumber, buf: &mut [u8]) -> std::io::Result<()>;
-    /// Writes provided buffer into the specified block number. Attempting to write
-    /// a block out of range will return an error.
     /// Writes provided buffer into the specified block number.
     ///
     /// # Errors
     ///
     /// Attempting to write a block out of range will return an error.
-    fn write_block(&mut self, blocknr: BlockNumber, buf: &mut [u8]) -> std::io::Result<()>;
+    fn write_block(&mut self, blocknr: BlockNumber, buf: &[u8]) -> std::io::Result<()>;
     /// Flush any buffered disk IO from memory. This is useful if it must guaranteed
     /// the disk writes actually occurred, for instance, if being re-read from
     /// disk.
     fn sync_disk(&mut self) -> std::io::Result<()>;
+    /// Orders writes, returning once every block written so far is on stable storage so
+    /// nothing written afterwards can land before them. Unlike `sync_disk` it doesn't need
+    /// to cover anything but block contents. Defaults to `sync_disk`.
+    fn barrier(&mut self) -> std::io::Result<()> {
+        self.sync_disk()
+    }
+    /// Writes a block with force unit access, returning once the block is on stable storage.
+    /// Devices able to do this without flushing every other outstanding write should
+    /// override it, the default writes the block and issues a `barrier`.
+    fn write_block_fua(&mut self, blocknr: BlockNumber, buf: &[u8]) -> std::io::Result<()> {
+        self.write_block(blocknr, buf)?;
+        self.barrier()
+    }
+    /// Tells the disk the contents of a block are no longer needed, like TRIM, so flash can
+    /// erase it and thin provisioned storage can reclaim it. Defaults to doing nothing.
+    fn discard(&mut self, _blocknr: BlockNumber) -> std::io::Result<()> {
+        Ok(())
+    }
+    /// Returns the total number of blocks available on the disk, or None if the disk can't
+    /// tell, in which case mounting skips checking the file system fits. Defaults to None.
+    fn block_count(&self) -> Option<usize> {
+        None
+    }
+    /// Returns true if blocks can't be written, for instance because the disk was opened
+    /// read-only. File systems on such disks are mounted read-only.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+    /// Takes an advisory lock on the disk, shared or exclusive, replacing any lock already
+    /// held. Fails with `ErrorKind::WouldBlock` instead of waiting if another opener holds a
+    /// conflicting lock. Defaults to doing nothing.
+    fn lock(&mut self, _exclusive: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Write(BlockNumber),
+        Barrier,
+        Sync,
+    }
+
+    /// Records the calls made to it in order, relying on the trait for everything else.
+    #[derive(Default)]
+    struct CallLog {
+        calls: Vec<Call>,
+    }
+
+    impl BlockStorage for CallLog {
+        fn open_disk<P: AsRef<Path>>(_path: P, _nblocks: usize) -> std::io::Result<Self> {
+            Ok(Self::default())
+        }
+
+        fn read_block(&mut self, _blocknr: BlockNumber, _buf: &mut [u8]) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn write_block(&mut self, blocknr: BlockNumber, _buf: &[u8]) -> std::io::Result<()> {
+            self.calls.push(Call::Write(blocknr));
+            Ok(())
+        }
+
+        fn sync_disk(&mut self) -> std::io::Result<()> {
+            self.calls.push(Call::Sync);
+            Ok(())
+        }
+
+        fn barrier(&mut self) -> std::io::Result<()> {
+            self.calls.push(Call::Barrier);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn default_fua_write_issues_a_barrier_after_the_write() {
+        let mut dev = CallLog::default();
+
+        dev.write_block_fua(3, &[0x55; 4096]).unwrap();
+
+        assert_eq!(dev.calls, vec![Call::Write(3), Call::Barrier]);
+    }
 }
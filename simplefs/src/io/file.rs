@@ -2,6 +2,7 @@ use super::block::{BlockNumber, BlockStorage};
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::{BufWriter, ErrorKind, SeekFrom};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
 /// 4k is a common block size for file systems. Disks commonly are composed of
@@ -29,8 +30,21 @@ impl BlockStorage for FileBlockEmulator {
     where
         Self: std::marker::Sized,
     {
-        // Return error if the file does not exist rather than create one.
-        let file = OpenOptions::new().write(true).open(dest)?;
+        // Return error if the file does not exist rather than create one. Files that can't
+        // be written are opened read-only instead.
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dest.as_ref())
+        {
+            Err(e)
+                if e.kind() == ErrorKind::PermissionDenied
+                    || e.raw_os_error() == Some(libc::EROFS) =>
+            {
+                OpenOptions::new().read(true).open(dest)?
+            }
+            file => file?,
+        };
         let emu = FileBlockEmulator {
             fd: file,
             block_count: nblocks,
@@ -64,7 +78,7 @@ impl BlockStorage for FileBlockEmulator {
         Ok(())
     }
     /// This method truncates writes that exceed the total block size.
-    fn write_block(&mut self, blocknr: BlockNumber, buf: &mut [u8]) -> std::io::Result<()> {
+    fn write_block(&mut self, blocknr: BlockNumber, buf: &[u8]) -> std::io::Result<()> {
         if blocknr > (self.block_count - 1) {
             return Err(std::io::Error::new(
                 ErrorKind::InvalidInput,
@@ -88,6 +102,59 @@ impl BlockStorage for FileBlockEmulator {
         self.fd.sync_all()?;
         Ok(())
     }
+
+    /// Uses fdatasync, skipping the file metadata `sync_disk` also flushes since the file
+    /// never changes size.
+    fn barrier(&mut self) -> std::io::Result<()> {
+        self.fd.sync_data()
+    }
+
+    fn block_count(&self) -> Option<usize> {
+        Some(self.block_count)
+    }
+
+    /// Checks the access mode the file was opened with.
+    fn is_read_only(&self) -> bool {
+        // F_GETFL only reads the flags of a descriptor we own.
+        let flags = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_GETFL) };
+        flags != -1 && flags & libc::O_ACCMODE == libc::O_RDONLY
+    }
+
+    /// Punches a hole over the block, so the file stops storing its contents. Files on file
+    /// systems unable to punch holes keep them.
+    fn discard(&mut self, blocknr: BlockNumber) -> std::io::Result<()> {
+        if blocknr > (self.block_count - 1) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "block out of range",
+            ));
+        }
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let offset = (blocknr * BLOCK_SIZE_BYTES) as libc::off_t;
+        let len = BLOCK_SIZE_BYTES as libc::off_t;
+        // fallocate only changes the file behind a descriptor we own.
+        if unsafe { libc::fallocate(self.fd.as_raw_fd(), mode, offset, len) } == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Uses flock(2), so the lock belongs to the open file and goes away with it.
+    fn lock(&mut self, exclusive: bool) -> std::io::Result<()> {
+        let op = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // flock only changes the lock held through a descriptor we own.
+        if unsafe { libc::flock(self.fd.as_raw_fd(), op | libc::LOCK_NB) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
 }
 
 pub struct FileBlockEmulatorBuilder {
@@ -171,8 +238,8 @@ mod tests {
         disk_emu.sync_disk().unwrap();
 
         // Allocate a block with a non-zero character.
-        let mut block = vec![0x55; 4096];
-        disk_emu.write_block(2, block.as_mut_slice()).unwrap();
+        let block = vec![0x55; 4096];
+        disk_emu.write_block(2, block.as_slice()).unwrap();
         disk_emu.sync_disk().unwrap();
 
         let mut read_block = vec![0x00; 4096];
@@ -186,6 +253,25 @@ mod tests {
         assert_eq!(filled_block, vec![0x55; 4096]);
     }
 
+    #[test]
+    fn discarded_blocks_read_back_as_zeros() {
+        let mut disk_emu = FileBlockEmulatorBuilder::from(tempfile::tempfile().unwrap())
+            .with_block_size(4)
+            .build()
+            .unwrap();
+        disk_emu.write_block(1, &[0x55; 4096]).unwrap();
+        disk_emu.write_block(2, &[0x55; 4096]).unwrap();
+
+        disk_emu.discard(1).unwrap();
+
+        let mut block = vec![0; 4096];
+        disk_emu.read_block(1, &mut block).unwrap();
+        assert_eq!(block, vec![0; 4096]);
+        disk_emu.read_block(2, &mut block).unwrap();
+        assert_eq!(block, vec![0x55; 4096]);
+        assert!(disk_emu.discard(4).is_err());
+    }
+
     #[test]
     fn can_read_and_write_start_and_end_blocks() {
         let fs_block = tempfile::tempfile().unwrap();
@@ -195,8 +281,8 @@ mod tests {
             .expect("failed to allocate file block");
         disk_emu.sync_disk().unwrap();
 
-        let mut block = vec![0x55; 4096];
-        disk_emu.write_block(0, block.as_mut_slice()).unwrap();
+        let block = vec![0x55; 4096];
+        disk_emu.write_block(0, block.as_slice()).unwrap();
         disk_emu.sync_disk().unwrap();
 
         let mut read_block = vec![0x00; 4096];
@@ -204,8 +290,8 @@ mod tests {
         assert_eq!(read_block, vec![0x55; 4096]);
 
         // Allocate a block with a non-zero character.
-        let mut block = vec![0x55; 4096];
-        disk_emu.write_block(1, block.as_mut_slice()).unwrap();
+        let block = vec![0x55; 4096];
+        disk_emu.write_block(1, block.as_slice()).unwrap();
         disk_emu.sync_disk().unwrap();
 
         let mut read_block = vec![0x00; 4096];
@@ -214,6 +300,23 @@ mod tests {
         assert_eq!(read_block, vec![0x55; 4096]);
     }
 
+    #[test]
+    fn files_opened_read_only_are_detected() {
+        let fs_block = tempfile::NamedTempFile::new().unwrap();
+        let writable = FileBlockEmulatorBuilder::from(fs_block.reopen().unwrap())
+            .with_block_size(1)
+            .build()
+            .unwrap();
+        assert!(!writable.is_read_only());
+
+        let read_only = FileBlockEmulatorBuilder::from(File::open(fs_block.path()).unwrap())
+            .with_block_size(1)
+            .clear_medium(false)
+            .build()
+            .unwrap();
+        assert!(read_only.is_read_only());
+    }
+
     #[test]
     fn read_block_beyond_range_throws_exception() {
         let fs_block = tempfile::tempfile().unwrap();
@@ -226,8 +329,8 @@ mod tests {
         disk_emu.sync_disk().unwrap();
 
         // Attempt to write beyond range.
-        let mut block = vec![0x55; 4096];
-        let wresult = disk_emu.write_block(1, block.as_mut_slice());
+        let block = vec![0x55; 4096];
+        let wresult = disk_emu.write_block(1, block.as_slice());
         if wresult.is_ok() {
             panic!("expected an error, got result instead")
         }
@@ -245,9 +348,9 @@ mod tests {
         disk_emu.sync_disk().unwrap();
 
         // Fill half the block with meaningful data.
-        let mut block = vec![0x55; 2048];
+        let block = vec![0x55; 2048];
         disk_emu
-            .write_block(0, block.as_mut_slice())
+            .write_block(0, block.as_slice())
             .expect("failed to write block");
         disk_emu.sync_disk().unwrap();
     }
@@ -2,9 +2,25 @@
 extern crate log;
 
 mod alloc;
+mod dcache;
 mod fs;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
 pub mod io;
 mod node;
+mod options;
+mod path;
 mod sb;
+mod watch;
 
-pub use fs::SFS;
+pub use dcache::CacheStats;
+pub use fs::{
+    DefragReport, DirUsage, FileHandle, FsStats, InodeStat, IoStats, OpenMode, SFSError, SFS,
+};
+pub use node::{
+    APPEND_FL, CASEFOLD_FL, FIRST_INODE, IMMUTABLE_FL, JOURNAL_INODE, LOST_FOUND_INODE,
+    QUOTA_INODE, ROOT_INODE, TRASH_INODE,
+};
+pub use options::{AtimePolicy, ErrorPolicy, MountOptions, SfsOptions, SyncPolicy};
+pub use path::SfsPath;
+pub use watch::{Event, EventKind};
@@ -1,18 +1,60 @@
+use std::collections::hash_map::RandomState;
 use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime};
 
 use crate::alloc::{Bitmap, NextAvailableAllocation, State};
+use crate::fs::SFSError;
 
 use zerocopy::{AsBytes, FromBytes};
 
 const BLOCK_SIZE: u32 = 4096;
 const NODE_SIZE: u32 = 256;
 const NODES_PER_BLOCK: u32 = BLOCK_SIZE / NODE_SIZE;
+/// The number of data block pointers stored directly in an inode.
+pub const DIRECT_BLOCKS: usize = 15;
 const ROOT_DEFAULT_MODE: u16 = 0x4000;
-const DEFAULT_MODE: u16 = 0x2000;
+const DEFAULT_MODE: u16 = 0x8000;
+const DIR_DEFAULT_MODE: u16 = 0x4000;
+const FIFO_MODE: u16 = 0x1000;
+/// Character devices. The original format gave this type to every inode but the root.
+pub(crate) const CHR_MODE: u16 = 0x2000;
+const BLK_MODE: u16 = 0x6000;
+const SOCK_MODE: u16 = 0xC000;
+pub(crate) const FILE_TYPE_MASK: u16 = 0xF000;
+/// The file types mknod(2) is able to create.
+pub(crate) const MKNOD_TYPES: [u16; 5] = [DEFAULT_MODE, FIFO_MODE, CHR_MODE, BLK_MODE, SOCK_MODE];
+const SETUID: u16 = 0o4000;
+const SETGID: u16 = 0o2000;
+const PERMISSION_MASK: u16 = 0o7777;
+
+/// The root directory.
+pub const ROOT_INODE: u32 = 0;
+/// Where fsck reconnects orphaned inodes.
+pub const LOST_FOUND_INODE: u32 = 1;
+/// Reserved for a metadata journal.
+pub const JOURNAL_INODE: u32 = 2;
+/// Reserved for quota accounting.
+pub const QUOTA_INODE: u32 = 3;
+/// Reserved for a trash directory holding deleted files.
+pub const TRASH_INODE: u32 = 4;
+/// The first inode number handed out to ordinary files and directories. Everything below is
+/// reserved for the special objects above, whether or not they exist yet. Images formatted
+/// before the reservation hand out every inode number after the root.
+pub const FIRST_INODE: u32 = 5;
+
+/// The inode can't be modified, matching FS_IMMUTABLE_FL used by chattr.
+pub const IMMUTABLE_FL: u32 = 0x10;
+/// The inode can only be appended to, matching FS_APPEND_FL used by chattr.
+pub const APPEND_FL: u32 = 0x20;
+/// Name lookups in the directory ignore case, matching FS_CASEFOLD_FL used by chattr.
+pub const CASEFOLD_FL: u32 = 0x4000_0000;
+/// Every attribute flag the file system understands.
+pub(crate) const SUPPORTED_FLAGS: u32 = IMMUTABLE_FL | APPEND_FL | CASEFOLD_FL;
 
 #[repr(C)]
 #[derive(AsBytes, FromBytes, Copy, Clone)]
-/// This structure __must not exceed 256 bytes.__
+/// This structure __must not exceed 256 bytes.__ Every field is stored little-endian on disk.
 pub struct Inode {
     /// The file mode (e.g full access - drwxrwxrwx).
     mode: u16,
@@ -23,19 +65,27 @@ pub struct Inode {
     /// The number of links to this file.
     links_count: u16,
     /// The total size of the file in bytes.
-    size: u32,
-    /// The time the file was created in milliseconds since epoch.
+    pub size: u32,
+    /// The time the file was created in seconds since epoch.
     create_time: u32,
-    /// The time the file was last updated in milliseconds since epoch.
+    /// The time the file was last updated in seconds since epoch.
     update_time: u32,
-    /// The time the file was last accessed in milliseconds since epoch.
+    /// The time the file was last accessed in seconds since epoch.
     access_time: u32,
+    /// Attribute flags such as `IMMUTABLE_FL`, carved out of the padding so older images
+    /// read as having no flags set.
+    pub flags: u32,
+    /// The device number of a character or block special file.
+    rdev: u32,
+    /// Tells apart successive files using the same inumber, so handles to a deleted file
+    /// don't resolve to whatever replaces it.
+    generation: u32,
     /// Reserved for future expansion of file attributes up to 256 byte limit.
     // TODO(allancalix): Fill in the rest of the metadata like  symlink information etc.
-    padding: [u32; 43],
+    padding: [u32; 40],
     /// Pointers for the data blocks that belong to the file. Uses the remaining
     /// space the 256 inode space.
-    pub blocks: [u32; 15],
+    pub blocks: [u32; DIRECT_BLOCKS],
 }
 
 impl Inode {
@@ -49,8 +99,11 @@ impl Inode {
             create_time: 0,
             update_time: 0,
             access_time: 0,
-            padding: [0; 43],
-            blocks: [0; 15],
+            flags: 0,
+            rdev: 0,
+            generation: 0,
+            padding: [0; 40],
+            blocks: [0; DIRECT_BLOCKS],
         }
     }
 
@@ -64,20 +117,155 @@ impl Inode {
             create_time: 0,
             update_time: 0,
             access_time: 0,
-            padding: [0; 43],
-            blocks: [0; 15],
+            flags: 0,
+            rdev: 0,
+            generation: 0,
+            padding: [0; 40],
+            blocks: [0; DIRECT_BLOCKS],
+        }
+    }
+
+    fn directory() -> Self {
+        Self {
+            mode: DIR_DEFAULT_MODE,
+            ..Self::default()
+        }
+    }
+
+    fn parse(buf: &[u8]) -> Result<Self, SFSError> {
+        let mut inode = Inode::default();
+        let len = inode.as_bytes().len();
+        if buf.len() < len {
+            return Err(SFSError::Corrupted("inode is truncated".to_string()));
+        }
+        inode.as_bytes_mut().copy_from_slice(&buf[..len]);
+        Ok(inode.swap_le())
+    }
+
+    /// Converts every field between host and little-endian byte order. The conversion is
+    /// its own inverse so it's used both after reading and before writing.
+    fn swap_le(self) -> Self {
+        Self {
+            mode: self.mode.to_le(),
+            uid: self.uid.to_le(),
+            gid: self.gid.to_le(),
+            links_count: self.links_count.to_le(),
+            size: self.size.to_le(),
+            create_time: self.create_time.to_le(),
+            update_time: self.update_time.to_le(),
+            access_time: self.access_time.to_le(),
+            flags: self.flags.to_le(),
+            rdev: self.rdev.to_le(),
+            generation: self.generation.to_le(),
+            padding: self.padding.map(u32::to_le),
+            blocks: self.blocks.map(u32::to_le),
+        }
+    }
+
+    /// Returns true if the inode describes a directory.
+    pub fn is_dir(&self) -> bool {
+        self.mode & FILE_TYPE_MASK == DIR_DEFAULT_MODE
+    }
+
+    /// Sets the permission bits of a newly created inode from the mode requested by the caller
+    /// and its umask, following the rules of open(2) and mkdir(2). Inside a setgid `parent` the
+    /// inode joins the parent's group, and directories inherit the setgid bit.
+    pub fn init_mode(&mut self, requested: u16, umask: u16, parent: &Inode) {
+        let mut permissions = requested & !umask & PERMISSION_MASK;
+        if self.is_dir() {
+            // mkdir ignores requests for setuid and setgid, the latter is only inherited.
+            permissions &= !(SETUID | SETGID);
+        }
+        self.mode = (self.mode & FILE_TYPE_MASK) | permissions;
+
+        if parent.mode & SETGID != 0 {
+            self.gid = parent.gid;
+            if self.is_dir() {
+                self.mode |= SETGID;
+            }
         }
     }
 
-    fn parse(buf: &[u8]) -> Self {
-        let inode = buf.as_ptr() as *const Inode;
-        unsafe { *inode }
+    /// Returns the file type and permission bits.
+    pub fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    /// Returns the device number of a character or block special file, 0 for anything else.
+    pub fn rdev(&self) -> u32 {
+        self.rdev
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Returns the time the file was last accessed.
+    pub fn atime(&self) -> SystemTime {
+        from_epoch_secs(self.access_time)
+    }
+
+    pub fn set_atime(&mut self, time: SystemTime) {
+        self.access_time = to_epoch_secs(time);
+    }
+
+    /// Returns the time the file was last updated.
+    pub fn mtime(&self) -> SystemTime {
+        from_epoch_secs(self.update_time)
+    }
+
+    pub fn set_mtime(&mut self, time: SystemTime) {
+        self.update_time = to_epoch_secs(time);
+    }
+
+    /// Stamps a new file with `now` as the time it was created, last updated and accessed.
+    pub fn init_times(&mut self, now: SystemTime) {
+        let now = to_epoch_secs(now);
+        self.create_time = now;
+        self.update_time = now;
+        self.access_time = now;
+    }
+
+    /// Returns the ids of the owning user and group.
+    pub fn owner(&self) -> (u16, u16) {
+        (self.uid, self.gid)
+    }
+
+    pub fn set_owner(&mut self, uid: u16, gid: u16) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    /// Returns true if the inode has the immutable attribute set.
+    pub fn is_immutable(&self) -> bool {
+        self.flags & IMMUTABLE_FL != 0
+    }
+
+    /// Returns true if names in the directory are looked up without regard to case.
+    pub fn is_casefold(&self) -> bool {
+        self.flags & CASEFOLD_FL != 0
     }
 }
 
+fn from_epoch_secs(secs: u32) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs.into())
+}
+
+/// Truncates `time` to whole seconds, clamped to what an inode can store.
+fn to_epoch_secs(time: SystemTime) -> u32 {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    secs.min(u32::MAX.into()) as u32
+}
+
+#[derive(Clone)]
 pub struct InodeGroup {
     nodes: BTreeMap<u32, Inode>,
     alloc_tracker: Bitmap,
+    /// The lowest inumber handed out to ordinary files and directories.
+    first_inode: u32,
 }
 
 impl InodeGroup {
@@ -85,19 +273,33 @@ impl InodeGroup {
         let mut group = Self {
             nodes: BTreeMap::new(),
             alloc_tracker,
+            first_inode: FIRST_INODE,
         };
 
-        group.insert(0, Inode::root());
+        group.insert(ROOT_INODE, Inode::root());
         group
     }
 
-    pub fn open(alloc_tracker: Bitmap) -> Self {
+    /// Opens an existing inode table that hands out inumbers from `first_inode` on, either
+    /// `FIRST_INODE` or, for images that predate the reserved inodes, the one after the root.
+    pub fn open(alloc_tracker: Bitmap, first_inode: u32) -> Self {
         Self {
             nodes: BTreeMap::new(),
             alloc_tracker,
+            first_inode,
         }
     }
 
+    /// Returns the lowest inumber handed out to ordinary files and directories.
+    pub fn first_inode(&self) -> u32 {
+        self.first_inode
+    }
+
+    /// Returns true if the inumbers below `FIRST_INODE` are reserved for special objects.
+    pub fn reserves_inodes(&self) -> bool {
+        self.first_inode == FIRST_INODE
+    }
+
     pub fn get(&self, inum: u32) -> Option<&Inode> {
         self.nodes.get(&inum)
     }
@@ -106,6 +308,11 @@ impl InodeGroup {
         self.nodes.get_mut(&inum)
     }
 
+    /// Iterates over every allocated inode in ascending inumber order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Inode)> {
+        self.nodes.iter().map(|(&inum, node)| (inum, node))
+    }
+
     pub fn allocations(&self) -> &Bitmap {
         &self.alloc_tracker
     }
@@ -116,43 +323,80 @@ impl InodeGroup {
     }
 
     /// Allocates a regular file Inode into the table and returns the new reserved node allocation
-    /// block index (i.e. the inumber). Panics if there is no space left to allocate another node.
-    pub fn new_file(&mut self) -> u32 {
+    /// block index (i.e. the inumber). Returns None if there is no space left to allocate another
+    /// node.
+    pub fn new_file(&mut self) -> Option<u32> {
+        self.allocate(Inode::default())
+    }
+
+    /// Allocates a directory Inode into the table and returns its inumber. Returns None if there
+    /// is no space left to allocate another node.
+    pub fn new_dir(&mut self) -> Option<u32> {
+        self.allocate(Inode::directory())
+    }
+
+    /// Allocates a special file of `file_type`, one of `MKNOD_TYPES`, and returns its inumber.
+    /// The device number `rdev` is only kept for character and block devices.
+    pub fn new_special(&mut self, file_type: u16, rdev: u32) -> Option<u32> {
+        let is_device = file_type == CHR_MODE || file_type == BLK_MODE;
+        self.allocate(Inode {
+            mode: file_type,
+            rdev: if is_device { rdev } else { 0 },
+            ..Inode::default()
+        })
+    }
+
+    /// Allocates the directory reserved for reconnecting orphans at `LOST_FOUND_INODE`.
+    /// Returns None if it's already allocated or the image doesn't reserve it.
+    pub fn new_lost_found(&mut self) -> Option<u32> {
+        if !self.reserves_inodes()
+            || self.alloc_tracker.get(LOST_FOUND_INODE as usize) == State::Used
+        {
+            return None;
+        }
+        let mut node = Inode::directory();
+        node.generation = random_generation();
+        self.insert(LOST_FOUND_INODE, node);
+        Some(LOST_FOUND_INODE)
+    }
+
+    fn allocate(&mut self, mut node: Inode) -> Option<u32> {
         // TODO(allancalix): The cap for this is hardcoded to support 5 blocks of inodes. Update when
         // the 5 block restriction is lifted.
         let mut alloc_gen =
             NextAvailableAllocation::new(self.alloc_tracker, Some(NODES_PER_BLOCK as usize * 5));
-        let inum = alloc_gen.next();
-        if inum.is_none() {
-            panic!("No free space left to allocate nodes.")
-        }
-
-        let inum = inum.unwrap() as u32;
-        let new_node = Inode::default();
-        self.insert(inum, new_node);
-        inum
+        let first_inode = self.first_inode as usize;
+        let inum = alloc_gen.find(|&inum| inum >= first_inode)? as u32;
+        node.generation = random_generation();
+        self.insert(inum, node);
+        Some(inum)
     }
+
     /// Loads a disk block of inodes into the in-memory tree.
-    pub fn load_block(&mut self, disk_block: u32, block_buf: &[u8]) {
+    pub fn load_block(&mut self, disk_block: u32, block_buf: &[u8]) -> Result<(), SFSError> {
+        if block_buf.len() < BLOCK_SIZE as usize {
+            return Err(SFSError::Corrupted("inode block is truncated".to_string()));
+        }
         let block_start = disk_block * NODES_PER_BLOCK;
         let block_end = block_start + NODES_PER_BLOCK;
         for i in block_start..block_end {
             if let State::Used = self.alloc_tracker.get(i as usize) {
-                let node_offset = block_start as usize % NODE_SIZE as usize;
-                let node = Inode::parse(&block_buf[node_offset..NODE_SIZE as usize]);
+                let node_offset = ((i - block_start) * NODE_SIZE) as usize;
+                let node = Inode::parse(&block_buf[node_offset..node_offset + NODE_SIZE as usize])?;
                 self.nodes.insert(i, node);
             }
         }
+        Ok(())
     }
 
     /// Serializes an entire disk block of inodes for writing to disk.
     pub fn serialize_block(&self, disk_block: u32) -> Vec<u8> {
         let mut block_buf = vec![0; 4096];
         let offset = disk_block * NODES_PER_BLOCK;
-        for (i, node) in self.nodes.range(offset..NODES_PER_BLOCK) {
-            let node_offset = *i as usize * NODE_SIZE as usize;
+        for (i, node) in self.nodes.range(offset..offset + NODES_PER_BLOCK) {
+            let node_offset = ((*i - offset) * NODE_SIZE) as usize;
             block_buf[node_offset..node_offset + NODE_SIZE as usize]
-                .copy_from_slice(node.as_bytes());
+                .copy_from_slice(node.swap_le().as_bytes());
         }
 
         block_buf
@@ -170,6 +414,18 @@ impl InodeGroup {
     }
 }
 
+/// Picks a generation number for a new inode. Like ext4 it's random rather than a counter, so
+/// nothing has to be persisted to keep numbers from repeating across remounts. Zero is left
+/// for inodes created before generations were recorded.
+fn random_generation() -> u32 {
+    loop {
+        let generation = RandomState::new().build_hasher().finish() as u32;
+        if generation != 0 {
+            return generation;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,11 +437,56 @@ mod tests {
         // Change some values.
         root.uid = 100;
         root.gid = 100;
+        root.flags = IMMUTABLE_FL | APPEND_FL;
 
-        let parsed_root = Inode::parse(root.clone().as_bytes());
+        let parsed_root = Inode::parse(root.swap_le().as_bytes()).unwrap();
 
         assert_eq!(root.uid, parsed_root.uid);
         assert_eq!(root.gid, parsed_root.gid);
+        assert!(parsed_root.is_immutable());
+        assert_eq!(parsed_root.flags, IMMUTABLE_FL | APPEND_FL);
+    }
+
+    #[test]
+    fn encodes_fields_little_endian() {
+        let mut group = InodeGroup::new(Bitmap::new());
+        let node = group.get_mut(0).unwrap();
+        node.size = 0x0102_0304;
+        node.blocks[0] = 0x0A0B_0C0D;
+        node.set_owner(0x1234, 0);
+
+        let block = group.serialize_block(0);
+        assert_eq!(&block[0..2], &[0x00, 0x40]);
+        assert_eq!(&block[2..4], &[0x34, 0x12]);
+        assert_eq!(&block[8..12], &[0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(&block[196..200], &[0x0D, 0x0C, 0x0B, 0x0A]);
+    }
+
+    #[test]
+    fn parsing_truncated_inode_returns_error() {
+        assert!(Inode::parse(&[0; 16]).is_err());
+    }
+
+    #[test]
+    fn can_load_a_serialized_inode_block() {
+        let mut group = InodeGroup::new(Bitmap::new());
+        for i in 0..NODES_PER_BLOCK + 2 {
+            let mut node = Inode::default();
+            node.uid = i as u16;
+            group.insert(i, node);
+        }
+
+        let mut loaded = InodeGroup::open(*group.allocations(), FIRST_INODE);
+        for disk_block in 0..2 {
+            loaded
+                .load_block(disk_block, &group.serialize_block(disk_block))
+                .unwrap();
+        }
+
+        assert_eq!(loaded.total_nodes(), NODES_PER_BLOCK as usize + 2);
+        for i in 0..NODES_PER_BLOCK + 2 {
+            assert_eq!(loaded.get(i).unwrap().uid, i as u16);
+        }
     }
 
     #[test]
@@ -200,4 +501,132 @@ mod tests {
         assert_eq!(group.get(1).unwrap().uid, 100);
         assert_eq!(group.get(1).unwrap().gid, 100);
     }
+
+    #[test]
+    fn directories_are_distinguished_from_files() {
+        let mut group = InodeGroup::new(Bitmap::new());
+        let file = group.new_file().unwrap();
+        let dir = group.new_dir().unwrap();
+
+        assert!(group.get(0).unwrap().is_dir());
+        assert!(!group.get(file).unwrap().is_dir());
+        assert!(group.get(dir).unwrap().is_dir());
+    }
+
+    #[test]
+    fn new_inodes_get_distinct_generations() {
+        let mut group = InodeGroup::new(Bitmap::new());
+        let a = group.new_file().unwrap();
+        let b = group.new_dir().unwrap();
+
+        let (a, b) = (group.get(a).unwrap(), group.get(b).unwrap());
+        assert_ne!(a.generation(), 0);
+        assert_ne!(a.generation(), b.generation());
+        assert_eq!(group.get(ROOT_INODE).unwrap().generation(), 0);
+    }
+
+    #[test]
+    fn only_device_files_keep_rdev() {
+        let mut group = InodeGroup::new(Bitmap::new());
+        let chr = group.new_special(CHR_MODE, 0x0105).unwrap();
+        let fifo = group.new_special(FIFO_MODE, 0x0105).unwrap();
+
+        assert_eq!(group.get(chr).unwrap().rdev(), 0x0105);
+        assert_eq!(group.get(chr).unwrap().mode(), CHR_MODE);
+        assert_eq!(group.get(fifo).unwrap().rdev(), 0);
+        assert!(!group.get(fifo).unwrap().is_dir());
+    }
+
+    #[test]
+    fn legacy_tables_hand_out_reserved_inodes() {
+        let mut root_only = Bitmap::new();
+        root_only.set_reserved(ROOT_INODE as usize);
+        let mut group = InodeGroup::open(root_only, ROOT_INODE + 1);
+
+        assert_eq!(group.new_dir(), Some(LOST_FOUND_INODE));
+        assert!(!group.reserves_inodes());
+        assert!(group.new_lost_found().is_none());
+    }
+
+    #[test]
+    fn allocation_fails_once_table_is_full() {
+        let mut group = InodeGroup::new(Bitmap::new());
+        for inum in FIRST_INODE..NODES_PER_BLOCK * 5 {
+            assert_eq!(group.new_file(), Some(inum));
+        }
+
+        assert!(group.new_file().is_none());
+    }
+
+    fn dir_with_mode(mode: u16, gid: u16) -> Inode {
+        let mut dir = Inode::directory();
+        dir.mode |= mode;
+        dir.gid = gid;
+        dir
+    }
+
+    #[test]
+    fn creation_mode_applies_umask() {
+        let parent = dir_with_mode(0o755, 0);
+        let mut file = Inode::default();
+        let mut dir = Inode::directory();
+
+        file.init_mode(0o666, 0o022, &parent);
+        dir.init_mode(0o777, 0o027, &parent);
+
+        assert_eq!(file.mode, DEFAULT_MODE | 0o644);
+        assert_eq!(dir.mode, DIR_DEFAULT_MODE | 0o750);
+    }
+
+    #[test]
+    fn creation_mode_ignores_requested_file_type() {
+        let parent = dir_with_mode(0o755, 0);
+        let mut file = Inode::default();
+
+        file.init_mode(DIR_DEFAULT_MODE | 0o600, 0, &parent);
+
+        assert!(!file.is_dir());
+        assert_eq!(file.mode, DEFAULT_MODE | 0o600);
+    }
+
+    #[test]
+    fn only_files_keep_requested_setid_bits() {
+        let parent = dir_with_mode(0o755, 0);
+        let mut file = Inode::default();
+        let mut dir = Inode::directory();
+
+        file.init_mode(SETUID | SETGID | 0o755, 0o022, &parent);
+        dir.init_mode(SETUID | SETGID | 0o1777, 0o022, &parent);
+
+        assert_eq!(file.mode, DEFAULT_MODE | SETUID | SETGID | 0o755);
+        // The sticky bit is still honored on directories.
+        assert_eq!(dir.mode, DIR_DEFAULT_MODE | 0o1755);
+    }
+
+    #[test]
+    fn setgid_directory_passes_on_its_group() {
+        let parent = dir_with_mode(SETGID | 0o775, 50);
+        let mut file = Inode::default();
+        let mut dir = Inode::directory();
+
+        file.init_mode(0o666, 0o002, &parent);
+        dir.init_mode(0o777, 0o002, &parent);
+
+        assert_eq!(file.gid, 50);
+        assert_eq!(file.mode, DEFAULT_MODE | 0o664);
+        assert_eq!(dir.gid, 50);
+        assert_eq!(dir.mode, DIR_DEFAULT_MODE | SETGID | 0o775);
+    }
+
+    #[test]
+    fn plain_directory_keeps_creator_group() {
+        let parent = dir_with_mode(0o775, 50);
+        let mut dir = Inode::directory();
+        dir.gid = 7;
+
+        dir.init_mode(0o777, 0o022, &parent);
+
+        assert_eq!(dir.gid, 7);
+        assert_eq!(dir.mode & SETGID, 0);
+    }
 }
@@ -0,0 +1,590 @@
+use crate::dcache;
+use crate::fs::{SFSError, SFS};
+use crate::io::BlockStorage;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// The umask applied to new files when none is given, matching the common login default.
+const DEFAULT_UMASK: u16 = 0o022;
+/// How long metadata changes may stay in memory before being written out, matching ext4.
+pub(crate) const DEFAULT_COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+/// How stale an access time may get under `relatime` before it is refreshed anyway.
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Controls when the access time of an inode is updated, see
+/// http://man7.org/linux/man-pages/man8/mount.8.html.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtimePolicy {
+    /// Never update access times.
+    NoAtime,
+    /// Only update the access time if it is older than the modify or change time, or
+    /// more than a day old.
+    RelAtime,
+    /// Update the access time on every access.
+    StrictAtime,
+}
+
+impl AtimePolicy {
+    /// Returns true if an access at `now` should update an inode with the given access,
+    /// modify and change times.
+    pub fn should_update(
+        self,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        now: SystemTime,
+    ) -> bool {
+        match self {
+            AtimePolicy::NoAtime => false,
+            AtimePolicy::StrictAtime => true,
+            AtimePolicy::RelAtime => {
+                atime <= mtime
+                    || atime <= ctime
+                    || now.duration_since(atime).unwrap_or_default() >= RELATIME_INTERVAL
+            }
+        }
+    }
+}
+
+impl fmt::Display for AtimePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AtimePolicy::NoAtime => "noatime",
+            AtimePolicy::RelAtime => "relatime",
+            AtimePolicy::StrictAtime => "strictatime",
+        })
+    }
+}
+
+/// When metadata changes buffered in memory are written to the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// At the end of every modifying operation, like mounting with `sync`.
+    Always,
+    /// Once the oldest change is older than the interval, checked at the end of every
+    /// operation and by `SFS::spawn_flusher`.
+    Periodic(Duration),
+    /// Only by `SFS::sync` and when unmounting, trading durability for the fewest writes.
+    Never,
+}
+
+/// What a mounted file system does after finding corruption, stored in the superblock
+/// like ext4's `errors=` setting and overridable per mount with the same option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    /// Report the error to the caller and keep going.
+    Continue,
+    /// Report the error and reject every further modification.
+    RemountReadOnly,
+    /// Abort the process.
+    Panic,
+}
+
+impl ErrorPolicy {
+    /// Decodes the superblock representation, returning None for unknown values.
+    pub(crate) fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(ErrorPolicy::Continue),
+            1 => Some(ErrorPolicy::RemountReadOnly),
+            2 => Some(ErrorPolicy::Panic),
+            _ => None,
+        }
+    }
+
+    /// Parses the value of the `errors=` mount option, named like ext4's.
+    fn parse(value: &str) -> Result<Self, SFSError> {
+        match value {
+            "continue" => Ok(ErrorPolicy::Continue),
+            "remount-ro" => Ok(ErrorPolicy::RemountReadOnly),
+            "panic" => Ok(ErrorPolicy::Panic),
+            _ => Err(SFSError::InvalidArgument(format!(
+                r#"invalid value "{}" for option errors"#,
+                value
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorPolicy::Continue => "continue",
+            ErrorPolicy::RemountReadOnly => "remount-ro",
+            ErrorPolicy::Panic => "panic",
+        }
+    }
+
+    pub(crate) fn to_raw(self) -> u32 {
+        match self {
+            ErrorPolicy::Continue => 0,
+            ErrorPolicy::RemountReadOnly => 1,
+            ErrorPolicy::Panic => 2,
+        }
+    }
+}
+
+/// Options controlling the behavior of a mounted file system, parsed from the
+/// comma separated list passed to `mount -o`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountOptions {
+    /// Rejects every operation that would modify the file system.
+    pub read_only: bool,
+    pub atime: AtimePolicy,
+    /// Allows users other than the one mounting the file system to access it.
+    pub allow_other: bool,
+    /// Asks the kernel to enforce permissions based on file modes.
+    pub default_permissions: bool,
+    /// Overwrites data blocks with zeros and discards them before returning them to the
+    /// allocator, so their old contents can't be recovered from the raw image. Until unlink
+    /// and truncate exist this only covers blocks vacated by `SFS::defrag`.
+    pub secure_delete: bool,
+    /// Presents every file as owned by this user instead of the stored owner.
+    pub uid: Option<u32>,
+    /// Presents every file as owned by this group instead of the stored group.
+    pub gid: Option<u32>,
+    /// When metadata changes held in memory are written to disk.
+    pub sync: SyncPolicy,
+    /// Overrides the error policy recorded in the superblock for this mount.
+    pub errors: Option<ErrorPolicy>,
+    /// Permission bits masked off of every file and directory created through the mount.
+    pub umask: u16,
+    /// Stores every new file as owned by this user, like NFS root squashing.
+    pub squash_uid: Option<u16>,
+    /// Stores every new file as owned by this group.
+    pub squash_gid: Option<u16>,
+    /// Options not understood by the file system, handed to FUSE untouched.
+    pub passthrough: Vec<String>,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            atime: AtimePolicy::RelAtime,
+            allow_other: false,
+            default_permissions: false,
+            secure_delete: false,
+            uid: None,
+            gid: None,
+            sync: SyncPolicy::Periodic(DEFAULT_COMMIT_INTERVAL),
+            errors: None,
+            umask: DEFAULT_UMASK,
+            squash_uid: None,
+            squash_gid: None,
+            passthrough: vec![],
+        }
+    }
+}
+
+impl MountOptions {
+    /// Applies a comma separated list of options on top of the current ones. Later
+    /// options win over earlier ones, so `ro,rw` mounts the file system writable.
+    pub fn apply(&mut self, opts: &str) -> Result<(), SFSError> {
+        for opt in opts.split(',').filter(|opt| !opt.is_empty()) {
+            let mut parts = opt.splitn(2, '=');
+            let key = parts.next().unwrap();
+            match (key, parts.next()) {
+                // Only tells `mount` to change the options of a mounted file system.
+                ("remount", None) => {}
+                ("ro", None) => self.read_only = true,
+                ("rw", None) => self.read_only = false,
+                ("noatime", None) => self.atime = AtimePolicy::NoAtime,
+                ("relatime", None) => self.atime = AtimePolicy::RelAtime,
+                ("strictatime", None) => self.atime = AtimePolicy::StrictAtime,
+                ("allow_other", None) => self.allow_other = true,
+                ("default_permissions", None) => self.default_permissions = true,
+                ("secure_delete", None) => self.secure_delete = true,
+                ("uid", Some(value)) => self.uid = Some(parse_u32(key, value)?),
+                ("gid", Some(value)) => self.gid = Some(parse_u32(key, value)?),
+                ("sync", None) => self.sync = SyncPolicy::Always,
+                ("async", None) => self.sync = SyncPolicy::Periodic(DEFAULT_COMMIT_INTERVAL),
+                ("commit", Some("never")) => self.sync = SyncPolicy::Never,
+                ("commit", Some(value)) => {
+                    let interval = Duration::from_secs(parse_u32(key, value)?.into());
+                    self.sync = SyncPolicy::Periodic(interval);
+                }
+                ("errors", Some(value)) => self.errors = Some(ErrorPolicy::parse(value)?),
+                ("umask", Some(value)) => self.umask = parse_umask(value)?,
+                ("squash_uid", Some(value)) => self.squash_uid = Some(parse_stored_id(key, value)?),
+                ("squash_gid", Some(value)) => self.squash_gid = Some(parse_stored_id(key, value)?),
+                _ => self.passthrough.push(opt.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the options the FUSE kernel module should receive, i.e. everything it
+    /// enforces itself plus any options the file system did not recognize.
+    pub fn fuse_options(&self) -> Vec<String> {
+        let mut opts = vec![];
+        if self.read_only {
+            opts.push("ro".to_string());
+        }
+        if self.allow_other {
+            opts.push("allow_other".to_string());
+        }
+        if self.default_permissions {
+            opts.push("default_permissions".to_string());
+        }
+        opts.extend(self.passthrough.iter().cloned());
+        opts
+    }
+}
+
+/// Lists every option in effect, defaults included, the way `/proc/mounts` does.
+impl fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut opts = vec![
+            if self.read_only { "ro" } else { "rw" }.to_string(),
+            self.atime.to_string(),
+        ];
+        if self.allow_other {
+            opts.push("allow_other".to_string());
+        }
+        if self.default_permissions {
+            opts.push("default_permissions".to_string());
+        }
+        if self.secure_delete {
+            opts.push("secure_delete".to_string());
+        }
+        if let Some(uid) = self.uid {
+            opts.push(format!("uid={}", uid));
+        }
+        if let Some(gid) = self.gid {
+            opts.push(format!("gid={}", gid));
+        }
+        match self.sync {
+            SyncPolicy::Always => opts.push("sync".to_string()),
+            SyncPolicy::Periodic(interval) if interval != DEFAULT_COMMIT_INTERVAL => {
+                opts.push(format!("commit={}", interval.as_secs()))
+            }
+            SyncPolicy::Periodic(_) => {}
+            SyncPolicy::Never => opts.push("commit=never".to_string()),
+        }
+        if let Some(policy) = self.errors {
+            opts.push(format!("errors={}", policy.name()));
+        }
+        if self.umask != DEFAULT_UMASK {
+            opts.push(format!("umask={:04o}", self.umask));
+        }
+        if let Some(uid) = self.squash_uid {
+            opts.push(format!("squash_uid={}", uid));
+        }
+        if let Some(gid) = self.squash_gid {
+            opts.push(format!("squash_gid={}", gid));
+        }
+        opts.extend(self.passthrough.iter().cloned());
+        f.write_str(&opts.join(","))
+    }
+}
+
+impl FromStr for MountOptions {
+    type Err = SFSError;
+
+    fn from_str(opts: &str) -> Result<Self, Self::Err> {
+        let mut options = Self::default();
+        options.apply(opts)?;
+        Ok(options)
+    }
+}
+
+/// Configures a file system as it's created or opened, e.g.
+/// `SfsOptions::new().read_only(true).open(dev)`. The options are validated before the
+/// device is touched and stay in effect for the lifetime of the returned instance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SfsOptions {
+    pub(crate) mount: MountOptions,
+    pub(crate) dcache_capacity: usize,
+    pub(crate) force: bool,
+}
+
+impl Default for SfsOptions {
+    fn default() -> Self {
+        Self {
+            mount: MountOptions::default(),
+            dcache_capacity: dcache::DEFAULT_CAPACITY,
+            force: false,
+        }
+    }
+}
+
+impl SfsOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces every mount option at once, e.g. with ones parsed from `mount -o`.
+    pub fn mount_options(mut self, options: MountOptions) -> Self {
+        self.mount = options;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.mount.read_only = read_only;
+        self
+    }
+
+    pub fn atime(mut self, policy: AtimePolicy) -> Self {
+        self.mount.atime = policy;
+        self
+    }
+
+    /// Sets when metadata changes held in memory are written out.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.mount.sync = policy;
+        self
+    }
+
+    /// Overrides the error policy recorded in the superblock while mounted.
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.mount.errors = Some(policy);
+        self
+    }
+
+    /// Sets the number of lookups the directory entry cache holds before starting over.
+    pub fn dcache_capacity(mut self, entries: usize) -> Self {
+        self.dcache_capacity = entries;
+        self
+    }
+
+    /// Goes ahead even if the storage is locked by another mount, which otherwise fails
+    /// with `Busy`. Read-only mounts share the storage, a read-write one needs it to itself.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Formats `dev` and returns the new file system configured with these options.
+    pub fn create<T: BlockStorage>(self, dev: T) -> Result<SFS<T>, SFSError> {
+        self.validate()?;
+        SFS::format(dev, self)
+    }
+
+    /// Opens the file system already on `dev` configured with these options.
+    pub fn open<T: BlockStorage>(self, dev: T) -> Result<SFS<T>, SFSError> {
+        self.validate()?;
+        SFS::load(dev, self)
+    }
+
+    fn validate(&self) -> Result<(), SFSError> {
+        if self.dcache_capacity == 0 {
+            return Err(SFSError::InvalidArgument(
+                "dentry cache capacity must be at least 1".to_string(),
+            ));
+        }
+        if self.mount.umask > 0o777 {
+            return Err(SFSError::InvalidArgument(format!(
+                "umask {:o} has bits beyond the permission bits",
+                self.mount.umask
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn parse_u32(key: &str, value: &str) -> Result<u32, SFSError> {
+    value.parse::<u32>().map_err(|_| {
+        SFSError::InvalidArgument(format!(r#"invalid value "{}" for option {}"#, value, key))
+    })
+}
+
+fn parse_umask(value: &str) -> Result<u16, SFSError> {
+    match u16::from_str_radix(value, 8) {
+        Ok(umask) if umask <= 0o777 => Ok(umask),
+        _ => Err(SFSError::InvalidArgument(format!(
+            r#"invalid value "{}" for option umask, must be octal permission bits"#,
+            value
+        ))),
+    }
+}
+
+/// Parses an id that is written to inodes, which only have room for 16 bit ids.
+fn parse_stored_id(key: &str, value: &str) -> Result<u16, SFSError> {
+    value.parse::<u16>().map_err(|_| {
+        SFSError::InvalidArgument(format!(
+            r#"invalid value "{}" for option {}, must fit in 16 bits"#,
+            value, key
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::OpenMode;
+    use crate::io::{FileBlockEmulator, FileBlockEmulatorBuilder};
+
+    #[test]
+    fn empty_options_use_defaults() {
+        let opts: MountOptions = "".parse().unwrap();
+
+        assert_eq!(opts, MountOptions::default());
+        assert_eq!(opts.atime, AtimePolicy::RelAtime);
+    }
+
+    #[test]
+    fn can_parse_known_options() {
+        let opts: MountOptions =
+            "ro,noatime,allow_other,default_permissions,secure_delete,uid=1000,gid=100"
+                .parse()
+                .unwrap();
+
+        assert!(opts.read_only);
+        assert_eq!(opts.atime, AtimePolicy::NoAtime);
+        assert!(opts.allow_other);
+        assert!(opts.default_permissions);
+        assert!(opts.secure_delete);
+        assert_eq!(opts.uid, Some(1000));
+        assert_eq!(opts.gid, Some(100));
+        assert!(opts.passthrough.is_empty());
+    }
+
+    #[test]
+    fn later_options_override_earlier_ones() {
+        let mut opts: MountOptions = "ro,noatime".parse().unwrap();
+        opts.apply("rw,relatime").unwrap();
+
+        assert!(!opts.read_only);
+        assert_eq!(opts.atime, AtimePolicy::RelAtime);
+    }
+
+    #[test]
+    fn unknown_options_are_passed_through_to_fuse() {
+        let opts: MountOptions = "allow_other,max_read=4096,auto_unmount".parse().unwrap();
+
+        assert_eq!(opts.passthrough, vec!["max_read=4096", "auto_unmount"]);
+        assert_eq!(
+            opts.fuse_options(),
+            vec!["allow_other", "max_read=4096", "auto_unmount"]
+        );
+    }
+
+    #[test]
+    fn invalid_id_returns_error() {
+        assert!("uid=root".parse::<MountOptions>().is_err());
+        assert!("gid=-1".parse::<MountOptions>().is_err());
+        assert!("squash_uid=70000".parse::<MountOptions>().is_err());
+    }
+
+    #[test]
+    fn can_parse_sync_policy() {
+        assert_eq!(
+            MountOptions::default().sync,
+            SyncPolicy::Periodic(Duration::from_secs(5))
+        );
+
+        let opts: MountOptions = "commit=0".parse().unwrap();
+        assert_eq!(opts.sync, SyncPolicy::Periodic(Duration::from_secs(0)));
+        assert_eq!(opts.to_string(), "rw,relatime,commit=0");
+        assert!("commit=soon".parse::<MountOptions>().is_err());
+
+        for (opts, policy) in [
+            ("sync", SyncPolicy::Always),
+            ("commit=never", SyncPolicy::Never),
+            ("sync,async", SyncPolicy::Periodic(DEFAULT_COMMIT_INTERVAL)),
+        ] {
+            let parsed: MountOptions = opts.parse().unwrap();
+            assert_eq!(parsed.sync, policy);
+            assert_eq!(parsed.to_string().parse::<MountOptions>().unwrap(), parsed);
+        }
+    }
+
+    #[test]
+    fn can_parse_error_policy_override() {
+        assert_eq!(MountOptions::default().errors, None);
+
+        let opts: MountOptions = "errors=remount-ro".parse().unwrap();
+        assert_eq!(opts.errors, Some(ErrorPolicy::RemountReadOnly));
+        assert_eq!(opts.to_string(), "rw,relatime,errors=remount-ro");
+        assert!("errors=ignore".parse::<MountOptions>().is_err());
+    }
+
+    #[test]
+    fn can_parse_umask() {
+        assert_eq!(MountOptions::default().umask, 0o022);
+
+        let opts: MountOptions = "umask=027".parse().unwrap();
+        assert_eq!(opts.umask, 0o027);
+        assert_eq!(opts.to_string(), "rw,relatime,umask=0027");
+
+        assert!("umask=999".parse::<MountOptions>().is_err());
+        assert!("umask=1777".parse::<MountOptions>().is_err());
+    }
+
+    #[test]
+    fn can_parse_squash_options() {
+        let opts: MountOptions = "squash_uid=1000,squash_gid=100".parse().unwrap();
+
+        assert_eq!(opts.squash_uid, Some(1000));
+        assert_eq!(opts.squash_gid, Some(100));
+        assert_eq!(
+            opts.to_string(),
+            "rw,relatime,squash_uid=1000,squash_gid=100"
+        );
+    }
+
+    #[test]
+    fn options_list_includes_defaults() {
+        assert_eq!(MountOptions::default().to_string(), "rw,relatime");
+
+        let opts: MountOptions = "ro,strictatime,uid=1000,max_read=4096".parse().unwrap();
+        assert_eq!(opts.to_string(), "ro,strictatime,uid=1000,max_read=4096");
+        assert_eq!(opts.to_string().parse::<MountOptions>().unwrap(), opts);
+    }
+
+    #[test]
+    fn relatime_only_updates_stale_access_times() {
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let hour = Duration::from_secs(60 * 60);
+        let policy = AtimePolicy::RelAtime;
+
+        // Not accessed since the last modification.
+        assert!(policy.should_update(mtime - hour, mtime, mtime, mtime + hour));
+        // Accessed since, and recently.
+        assert!(!policy.should_update(mtime + hour, mtime, mtime, mtime + 2 * hour));
+        // Accessed since, but over a day ago.
+        assert!(policy.should_update(mtime + hour, mtime, mtime, mtime + 26 * hour));
+
+        assert!(!AtimePolicy::NoAtime.should_update(mtime - hour, mtime, mtime, mtime));
+        assert!(AtimePolicy::StrictAtime.should_update(mtime + hour, mtime, mtime, mtime));
+    }
+
+    fn test_device() -> FileBlockEmulator {
+        FileBlockEmulatorBuilder::from(tempfile::tempfile().unwrap())
+            .with_block_size(64)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn builder_configures_the_file_system() {
+        let mut fs = SfsOptions::new()
+            .atime(AtimePolicy::NoAtime)
+            .dcache_capacity(1)
+            .create(test_device())
+            .unwrap();
+        fs.open("/a", OpenMode::RO).unwrap_err();
+        fs.open("/b", OpenMode::RO).unwrap_err();
+
+        assert_eq!(fs.mount_options().atime, AtimePolicy::NoAtime);
+        assert_eq!(fs.dcache_stats().entries, 1);
+    }
+
+    #[test]
+    fn read_only_builder_rejects_modifications() {
+        let mut fs = SfsOptions::new()
+            .read_only(true)
+            .create(test_device())
+            .unwrap();
+
+        assert_eq!(fs.mkdir("/foo").unwrap_err().to_errno(), libc::EROFS);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected_before_opening() {
+        // The device isn't formatted, so getting past validation would fail differently.
+        match SfsOptions::new().dcache_capacity(0).open(test_device()) {
+            Err(SFSError::InvalidArgument(_)) => (),
+            _ => panic!("Expected an invalid argument error."),
+        }
+    }
+}
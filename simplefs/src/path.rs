@@ -0,0 +1,202 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::fs::SFSError;
+
+/// The longest file name allowed in a directory, matching NAME_MAX on Linux.
+pub(crate) const MAX_NAME_LEN: u32 = 255;
+/// The longest path accepted by the file system, matching PATH_MAX on Linux.
+const MAX_PATH_LEN: usize = 4096;
+/// The deepest path accepted by the file system, counting the root.
+pub(crate) const MAX_PATH_DEPTH: usize = 128;
+
+/// Bytes that can't appear in a name because directories store one `<inumber>:<name>` entry
+/// per line.
+const FORBIDDEN_CHARS: [char; 3] = ['\0', '\n', '\r'];
+
+/// An absolute path that has already been checked against the file system's naming rules.
+/// Every component is valid UTF-8, no longer than `MAX_NAME_LEN` bytes and free of the
+/// characters directories can't store, and the path holds no `..` components. Redundant
+/// separators and `.` components are dropped, so `/foo//./bar/` becomes `/foo/bar`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SfsPath {
+    /// The normalized path, either `/` or `/`-prefixed components.
+    path: String,
+}
+
+impl SfsPath {
+    /// Validates `path`, returning `NameTooLong` if it or one of its components is too long
+    /// or it is too deep, and `InvalidArgument` for anything else that can't be stored.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, SFSError> {
+        let path = path.as_ref();
+        if path.as_os_str().len() > MAX_PATH_LEN {
+            return Err(SFSError::NameTooLong);
+        }
+        let mut parts = path.components();
+        if parts.next() != Some(Component::RootDir) {
+            return Err(SFSError::InvalidArgument(
+                "path must start with \"/\"".to_string(),
+            ));
+        }
+
+        let mut normalized = String::new();
+        let mut depth = 1;
+        for part in parts {
+            let name = match part {
+                Component::Normal(name) => name,
+                Component::CurDir => continue,
+                _ => {
+                    return Err(SFSError::InvalidArgument(format!(
+                        r#""{}" can't contain "..""#,
+                        path.display()
+                    )))
+                }
+            };
+            let name = name.to_str().ok_or_else(|| {
+                SFSError::InvalidArgument(format!(r#""{}" is not valid UTF-8"#, path.display()))
+            })?;
+            if name.len() > MAX_NAME_LEN as usize {
+                return Err(SFSError::NameTooLong);
+            }
+            if name.contains(&FORBIDDEN_CHARS[..]) {
+                return Err(SFSError::InvalidArgument(format!(
+                    "{:?} contains a character names can't hold",
+                    name
+                )));
+            }
+            depth += 1;
+            normalized.push('/');
+            normalized.push_str(name);
+        }
+        if depth > MAX_PATH_DEPTH {
+            return Err(SFSError::NameTooLong);
+        }
+        if normalized.is_empty() {
+            normalized.push('/');
+        }
+        Ok(SfsPath { path: normalized })
+    }
+
+    /// Returns the path of the root directory.
+    pub fn root() -> Self {
+        SfsPath {
+            path: "/".to_string(),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.path == "/"
+    }
+
+    /// Returns the names leading from the root to the file, the root itself excluded.
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.path.split('/').filter(|name| !name.is_empty())
+    }
+
+    /// Returns the path of the directory holding the file, or None for the root.
+    pub fn parent(&self) -> Option<SfsPath> {
+        if self.is_root() {
+            return None;
+        }
+        let end = self.path.rfind('/').unwrap();
+        Some(SfsPath {
+            path: if end == 0 {
+                "/".to_string()
+            } else {
+                self.path[..end].to_string()
+            },
+        })
+    }
+
+    /// Returns the last component of the path, or None for the root.
+    pub fn file_name(&self) -> Option<&str> {
+        self.components().last()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    pub fn to_path_buf(&self) -> PathBuf {
+        PathBuf::from(&self.path)
+    }
+}
+
+impl AsRef<Path> for SfsPath {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.path)
+    }
+}
+
+impl fmt::Display for SfsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+impl FromStr for SfsPath {
+    type Err = SFSError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SfsPath::new(s)
+    }
+}
+
+impl TryFrom<&Path> for SfsPath {
+    type Error = SFSError;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        SfsPath::new(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn normalizes_separators_and_current_dir() {
+        let path: SfsPath = "/foo//./bar/".parse().unwrap();
+
+        assert_eq!(path.as_str(), "/foo/bar");
+        assert_eq!(path.components().collect::<Vec<_>>(), vec!["foo", "bar"]);
+        assert_eq!(path.file_name(), Some("bar"));
+        assert_eq!(path.parent().unwrap().as_str(), "/foo");
+        assert_eq!(path.parent().unwrap().parent(), Some(SfsPath::root()));
+        assert_eq!(SfsPath::new("/").unwrap().parent(), None);
+        assert_eq!(SfsPath::root().file_name(), None);
+    }
+
+    #[test]
+    fn rejects_paths_that_cant_be_stored() {
+        for path in ["foo", "", "/foo/../bar", "/foo\nbar", "/foo\0", "/foo\r"] {
+            match SfsPath::new(path) {
+                Err(SFSError::InvalidArgument(_)) => {}
+                other => panic!("{:?} gave {:?}", path, other),
+            }
+        }
+        let not_utf8 = Path::new(OsStr::from_bytes(b"/foo\xff"));
+        assert!(matches!(
+            SfsPath::new(not_utf8),
+            Err(SFSError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn enforces_length_and_depth_limits() {
+        let longest = format!("/{}", "a".repeat(MAX_NAME_LEN as usize));
+        assert!(SfsPath::new(&longest).is_ok());
+        for path in [
+            format!("{}a", longest),
+            "/a".repeat(MAX_PATH_DEPTH),
+            format!("/{}", "a/".repeat(MAX_PATH_LEN / 2)),
+        ] {
+            assert!(matches!(SfsPath::new(&path), Err(SFSError::NameTooLong)));
+        }
+        assert!(SfsPath::new("/a".repeat(MAX_PATH_DEPTH - 1)).is_ok());
+    }
+}
@@ -1,5 +1,31 @@
+use crate::fs::SFSError;
 use zerocopy::{AsBytes, FromBytes};
 
+/// The newest on-disk format this build understands. Images from before the version was
+/// recorded read as version 0.
+pub(crate) const CURRENT_VERSION: u32 = 4;
+/// The first format version whose superblock carries a checksum.
+const CHECKSUM_VERSION: u32 = 2;
+/// The first format version with the `features` and `seal` fields.
+const FEATURES_VERSION: u32 = 3;
+/// The first format version keeping a second copy of the superblock, see `parse_block`.
+const SEQUENCE_VERSION: u32 = 4;
+/// The length of the version 2 superblock, which ends at the checksum.
+const V2_LEN: usize = 44;
+/// The length of the version 3 superblock, which ends at the seal.
+const V3_LEN: usize = 52;
+/// The unit a device writes atomically at best. The two copies of the superblock live in
+/// different sectors so a write torn between sectors leaves at least one of them intact.
+pub(crate) const SECTOR_SIZE: usize = 512;
+
+/// The image is sealed, see `SuperBlock::seal`.
+pub(crate) const SEALED_FEATURE: u32 = 0x1;
+/// Inode numbers below `FIRST_INODE` are reserved for special objects. Older images may have
+/// handed them out to ordinary files.
+pub(crate) const RESERVED_INODES_FEATURE: u32 = 0x2;
+/// Every feature flag this build understands.
+pub(crate) const SUPPORTED_FEATURES: u32 = SEALED_FEATURE | RESERVED_INODES_FEATURE;
+
 /// The first block of the file system storing information critical for mounting
 /// the file system and verifying the underlying disk is formatted correctly.
 ///
@@ -9,6 +35,8 @@ use zerocopy::{AsBytes, FromBytes};
 ///
 /// Some files, such as files with no data and symbolic links don't allocate any
 /// data blocks but do allocate inode blocks.
+///
+/// Every field is stored little-endian on disk regardless of the host.
 #[repr(C)]
 #[derive(Debug, PartialEq, AsBytes, FromBytes, Clone, Copy)]
 pub struct SuperBlock {
@@ -26,6 +54,21 @@ pub struct SuperBlock {
     pub free_inodes_count: u32,
     /// The index of the next available free block.
     pub free_list: u32,
+    /// The maximum length in bytes of a single file name.
+    pub max_name_len: u32,
+    /// What to do when corruption is found while mounted, see `ErrorPolicy`.
+    pub errors: u32,
+    /// The on-disk format revision, see `CURRENT_VERSION`.
+    pub version: u32,
+    /// CRC-32 of the superblock computed with this field zeroed, checked from
+    /// `CHECKSUM_VERSION` onwards.
+    pub checksum: u32,
+    /// Optional format features such as `SEALED_FEATURE`.
+    pub features: u32,
+    /// CRC-32 of every block after the superblock, recorded when the image was sealed.
+    pub seal: u32,
+    /// Incremented on every write of the superblock, picking the newest of its two copies.
+    pub sequence: u32,
 }
 
 impl SuperBlock {
@@ -38,27 +81,156 @@ impl SuperBlock {
             free_blocks_count: 0,
             free_inodes_count: 0,
             free_list: 0,
+            max_name_len: 0,
+            errors: 0,
+            version: 0,
+            checksum: 0,
+            features: 0,
+            seal: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Converts every field between host and little-endian byte order. The conversion is
+    /// its own inverse so it's used both after reading and before writing.
+    fn swap_le(self) -> Self {
+        Self {
+            sb_magic: self.sb_magic.to_le(),
+            inodes_count: self.inodes_count.to_le(),
+            blocks_count: self.blocks_count.to_le(),
+            reserved_blocks_count: self.reserved_blocks_count.to_le(),
+            free_blocks_count: self.free_blocks_count.to_le(),
+            free_inodes_count: self.free_inodes_count.to_le(),
+            free_list: self.free_list.to_le(),
+            max_name_len: self.max_name_len.to_le(),
+            errors: self.errors.to_le(),
+            version: self.version.to_le(),
+            checksum: self.checksum.to_le(),
+            features: self.features.to_le(),
+            seal: self.seal.to_le(),
+            sequence: self.sequence.to_le(),
         }
     }
 
     /// Attempts to parse a buffer as a SuperBlock returning a new owned instance
-    /// of the block. If the block is invalid, calling parse will cause a panic.
-    pub fn parse(buf: &[u8], magic: u32) -> Self {
-        let sb: *const SuperBlock = buf.as_ptr() as *const SuperBlock;
+    /// of the block. Returns an error if the buffer is too short to hold a
+    /// SuperBlock, the magic constant does not match, the format version is newer
+    /// than this build supports, or the checksum doesn't match the contents.
+    pub fn parse(buf: &[u8], magic: u32) -> Result<Self, SFSError> {
+        let mut sb = SuperBlock::new();
+        let len = sb.as_bytes().len();
+        if buf.len() < len {
+            return Err(SFSError::Corrupted("superblock is truncated".to_string()));
+        }
+        sb.as_bytes_mut().copy_from_slice(&buf[..len]);
+        let sb = sb.swap_le();
+
+        if sb.sb_magic != magic {
+            return Err(SFSError::BadMagic);
+        }
+        if sb.version > CURRENT_VERSION {
+            return Err(SFSError::UnsupportedVersion(sb.version));
+        }
+        if sb.version >= CHECKSUM_VERSION && sb.checksum != sb.compute_checksum() {
+            return Err(SFSError::BadChecksum);
+        }
+        Ok(sb)
+    }
 
-        unsafe {
-            assert_eq!(magic, (*sb).sb_magic, "Superblock magic constant invalid.");
-            *sb
+    /// Parses the first block of the image, which from `SEQUENCE_VERSION` on holds a copy of
+    /// the superblock in each of its first two sectors, written alternately. Returns the
+    /// newest intact copy, or the first copy's error if neither is intact.
+    pub fn parse_block(block: &[u8], magic: u32) -> Result<Self, SFSError> {
+        let primary = SuperBlock::parse(block, magic);
+        let backup = match block.get(SECTOR_SIZE..) {
+            Some(buf) => SuperBlock::parse(buf, magic).ok(),
+            None => None,
+        };
+        match (primary, backup) {
+            (Ok(primary), Some(backup))
+                if backup.version >= SEQUENCE_VERSION && backup.is_newer_than(&primary) =>
+            {
+                Ok(backup)
+            }
+            (Err(_), Some(backup)) if backup.version >= SEQUENCE_VERSION => Ok(backup),
+            (primary, _) => primary,
         }
     }
 
+    /// Serializes the superblock into the copy of the first block of the image its sequence
+    /// number selects, leaving the other copy as it was. Superblocks predating
+    /// `SEQUENCE_VERSION` only have the first copy.
+    pub fn write_copy(&self, block: &mut [u8]) {
+        let offset = if self.version >= SEQUENCE_VERSION {
+            (self.sequence % 2) as usize * SECTOR_SIZE
+        } else {
+            0
+        };
+        let sb = self.serialize();
+        block[offset..offset + sb.len()].copy_from_slice(&sb);
+    }
+
+    /// Compares sequence numbers allowing for them to wrap around.
+    fn is_newer_than(&self, other: &SuperBlock) -> bool {
+        (self.sequence.wrapping_sub(other.sequence) as i32) > 0
+    }
+
     /// Serializes the superblock into a series of bytes that can be sent or
-    /// deserialized back into a SuperBlock;
-    pub fn serialize(&self) -> &[u8] {
-        self.as_bytes()
+    /// deserialized back into a SuperBlock, filling in the checksum if the format
+    /// version has one.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut sb = *self;
+        if sb.version >= CHECKSUM_VERSION {
+            sb.checksum = sb.compute_checksum();
+        }
+        sb.swap_le().as_bytes().to_vec()
+    }
+
+    /// Returns true if the image was sealed and can't be modified.
+    pub fn is_sealed(&self) -> bool {
+        self.features & SEALED_FEATURE != 0
+    }
+
+    /// Returns true for images from before the format version was recorded.
+    pub fn is_unversioned(&self) -> bool {
+        self.version == 0
+    }
+
+    /// Returns true if the inode numbers below `FIRST_INODE` are reserved.
+    pub fn reserves_inodes(&self) -> bool {
+        self.features & RESERVED_INODES_FEATURE != 0
+    }
+
+    /// The checksum covers every field defined by the superblock's version, so fields added
+    /// later don't invalidate superblocks written before them.
+    fn compute_checksum(&self) -> u32 {
+        let mut sb = *self;
+        sb.checksum = 0;
+        let bytes = sb.swap_le();
+        let bytes = bytes.as_bytes();
+        if sb.version < FEATURES_VERSION {
+            crc32(0, &bytes[..V2_LEN])
+        } else if sb.version < SEQUENCE_VERSION {
+            crc32(0, &bytes[..V3_LEN])
+        } else {
+            crc32(0, bytes)
+        }
     }
 }
 
+/// Continues the CRC-32 (IEEE) `crc` of the bytes before `bytes`, starting from 0, a bit at
+/// a time. That's plenty fast for the superblock and for the occasional sealing of an image.
+pub(crate) fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,17 +243,175 @@ mod tests {
         sb.sb_magic = TEST_MAGIC; // non-zero superblock value.
         sb.inodes_count = 5;
         sb.blocks_count = 56;
+        sb.errors = 2;
         let encoded = sb.serialize();
 
-        let parsed = SuperBlock::parse(encoded, TEST_MAGIC);
+        let parsed = SuperBlock::parse(&encoded, TEST_MAGIC).unwrap();
 
         assert_eq!(parsed, sb);
     }
 
     #[test]
-    #[should_panic(expected = "Superblock magic constant invalid.")]
-    fn parsing_buffer_with_invalid_magic_panics() {
+    fn encodes_fields_little_endian() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.blocks_count = 0x0102_0304;
+        let encoded = sb.serialize();
+
+        assert_eq!(&encoded[0..4], &[0xEE, 0x4E, 0, 0]);
+        assert_eq!(&encoded[8..12], &[0x04, 0x03, 0x02, 0x01]);
+        let parsed = SuperBlock::parse(&encoded, TEST_MAGIC).unwrap();
+        assert_eq!(parsed.blocks_count, 0x0102_0304);
+    }
+
+    #[test]
+    fn parsing_buffer_with_invalid_magic_returns_error() {
         let zero_buffer_with_right_size = vec![0; 4096];
-        SuperBlock::parse(&zero_buffer_with_right_size, TEST_MAGIC);
+        assert!(matches!(
+            SuperBlock::parse(&zero_buffer_with_right_size, TEST_MAGIC),
+            Err(SFSError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn parsing_newer_version_returns_error() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.version = CURRENT_VERSION + 1;
+
+        assert!(matches!(
+            SuperBlock::parse(&sb.serialize(), TEST_MAGIC),
+            Err(SFSError::UnsupportedVersion(v)) if v == CURRENT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn parsing_damaged_superblock_fails_checksum() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.version = CURRENT_VERSION;
+        let mut encoded = sb.serialize();
+        assert!(SuperBlock::parse(&encoded, TEST_MAGIC).is_ok());
+
+        encoded[8] ^= 1;
+        assert!(matches!(
+            SuperBlock::parse(&encoded, TEST_MAGIC),
+            Err(SFSError::BadChecksum)
+        ));
+    }
+
+    #[test]
+    fn version_2_checksum_ignores_later_fields() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.version = 2;
+        let mut encoded = sb.serialize();
+
+        encoded[V2_LEN] ^= 1;
+        assert!(SuperBlock::parse(&encoded, TEST_MAGIC).is_ok());
+    }
+
+    #[test]
+    fn version_3_checksum_ignores_the_sequence() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.version = 3;
+        let mut encoded = sb.serialize();
+
+        encoded[V3_LEN] ^= 1;
+        assert!(SuperBlock::parse(&encoded, TEST_MAGIC).is_ok());
+    }
+
+    #[test]
+    fn newest_intact_copy_wins() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.version = CURRENT_VERSION;
+        let mut block = vec![0; 4096];
+        for sequence in 0..3 {
+            sb.sequence = sequence;
+            sb.write_copy(&mut block);
+        }
+        assert_eq!(
+            SuperBlock::parse_block(&block, TEST_MAGIC)
+                .unwrap()
+                .sequence,
+            2
+        );
+
+        // Tearing the write of sequence 2 leaves sequence 1 in the other sector.
+        block[8] ^= 1;
+        assert_eq!(
+            SuperBlock::parse_block(&block, TEST_MAGIC)
+                .unwrap()
+                .sequence,
+            1
+        );
+
+        block[SECTOR_SIZE + 8] ^= 1;
+        assert!(matches!(
+            SuperBlock::parse_block(&block, TEST_MAGIC),
+            Err(SFSError::BadChecksum)
+        ));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.version = CURRENT_VERSION;
+        let mut block = vec![0; 4096];
+        sb.sequence = u32::MAX;
+        sb.write_copy(&mut block);
+        sb.sequence = 0;
+        sb.write_copy(&mut block);
+
+        assert_eq!(
+            SuperBlock::parse_block(&block, TEST_MAGIC)
+                .unwrap()
+                .sequence,
+            0
+        );
+    }
+
+    #[test]
+    fn older_versions_only_use_the_first_copy() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.version = 3;
+        sb.sequence = 1;
+        let mut block = vec![0; 4096];
+        sb.write_copy(&mut block);
+
+        assert!(block[SECTOR_SIZE..].iter().all(|&b| b == 0));
+        assert_eq!(
+            SuperBlock::parse_block(&block, TEST_MAGIC)
+                .unwrap()
+                .sequence,
+            1
+        );
+    }
+
+    #[test]
+    fn unversioned_superblocks_skip_the_checksum() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+        sb.checksum = 0xDEAD;
+
+        assert!(SuperBlock::parse(&sb.serialize(), TEST_MAGIC).is_ok());
+    }
+
+    #[test]
+    fn crc32_matches_reference_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn parsing_truncated_buffer_returns_error() {
+        let mut sb = SuperBlock::new();
+        sb.sb_magic = TEST_MAGIC;
+
+        assert!(SuperBlock::parse(&sb.serialize()[..8], TEST_MAGIC).is_err());
     }
 }
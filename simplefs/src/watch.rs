@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// What happened to the file an `Event` refers to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    /// A file, directory or special file was added to a directory.
+    Created,
+    /// The attributes of an existing file changed.
+    Modified,
+}
+
+/// A change to the file system delivered to watchers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    /// The path the change was made through.
+    pub path: PathBuf,
+    pub ino: u32,
+}
+
+struct Watch {
+    path: PathBuf,
+    recursive: bool,
+    tx: Sender<Event>,
+}
+
+impl Watch {
+    /// Like inotify, a watch sees changes to the watched path itself and to the entries of
+    /// the directory at that path, or to everything beneath it when recursive.
+    fn matches(&self, path: &Path) -> bool {
+        if self.recursive {
+            return path.starts_with(&self.path);
+        }
+        path == self.path || path.parent() == Some(&self.path)
+    }
+}
+
+/// The set of active watches, each feeding events to the receiver handed out when it was
+/// added. Dropping the receiver removes the watch.
+#[derive(Default)]
+pub struct Watchers {
+    watches: Vec<Watch>,
+}
+
+impl Watchers {
+    pub fn add(&mut self, path: &Path, recursive: bool) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.watches.push(Watch {
+            path: path.to_path_buf(),
+            recursive,
+            tx,
+        });
+        rx
+    }
+
+    /// Sends `event` to every watch covering its path.
+    pub fn notify(&mut self, event: Event) {
+        self.watches.retain(|watch| {
+            if !watch.matches(&event.path) {
+                return true;
+            }
+            watch.tx.send(event.clone()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn created(path: &str) -> Event {
+        Event {
+            kind: EventKind::Created,
+            path: PathBuf::from(path),
+            ino: 5,
+        }
+    }
+
+    #[test]
+    fn watches_see_direct_children_unless_recursive() {
+        let mut watchers = Watchers::default();
+        let shallow = watchers.add(Path::new("/foo"), false);
+        let deep = watchers.add(Path::new("/foo"), true);
+
+        watchers.notify(created("/foo/bar"));
+        watchers.notify(created("/foo/bar/baz"));
+        watchers.notify(created("/foobar"));
+
+        assert_eq!(
+            shallow.try_iter().collect::<Vec<_>>(),
+            vec![created("/foo/bar")]
+        );
+        assert_eq!(
+            deep.try_iter().collect::<Vec<_>>(),
+            vec![created("/foo/bar"), created("/foo/bar/baz")]
+        );
+    }
+
+    #[test]
+    fn dropped_receivers_remove_their_watch() {
+        let mut watchers = Watchers::default();
+        let kept = watchers.add(Path::new("/"), true);
+        drop(watchers.add(Path::new("/"), true));
+
+        watchers.notify(created("/foo"));
+
+        assert_eq!(watchers.watches.len(), 1);
+        assert_eq!(kept.try_recv().unwrap(), created("/foo"));
+    }
+}